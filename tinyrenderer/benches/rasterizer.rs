@@ -0,0 +1,92 @@
+// Compares the edge function rasterizer against the original barycentric one,
+// on the diablo model's triangles, from near and far away:
+// - coverage: only finding which pixels each triangle covers (ie counting them)
+// - depth: rasterizing into a z-buffer (interpolation + depth test, but no shading); the z-buffer is only allocated once, then cleared
+// Run with `cargo bench`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use glam::*;
+use renderer::obj::parse_obj;
+use renderer::rasterizer::{bounding_box, rasterize, rasterize_barycentric, rasterize_barycentric_coverage, rasterize_coverage, screen_triangles, DrawStats, RasterState};
+use renderer::transform::{initialize_transform, Projection};
+
+const WIDTH: usize = 1024;
+const HEIGHT: usize = 1024;
+
+type ScreenTriangle = ([Vec4; 3], [Vec3; 3]);
+
+// the model's triangles in screen space, seen from `eye`
+fn screen_space_model(state: &RasterState, eye: Vec3) -> Vec<ScreenTriangle> {
+    let mesh = parse_obj("assets/diablo3_pose/diablo3_pose.obj").unwrap();
+    let transform = initialize_transform(HEIGHT, WIDTH, eye, Vec3::ZERO, Vec3::Y, Projection::default());
+    let mut stats = DrawStats::default();
+    mesh.faces()
+        .flat_map(|face| screen_triangles(state, &transform, face.vertices.map(|v| transform.clip_transform(v)), &mut stats))
+        .collect()
+}
+
+fn depth_test(zbuffer: &mut [f32], idx: usize, p_z: f32) {
+    if p_z > zbuffer[idx] {
+        zbuffer[idx] = p_z;
+    }
+}
+
+fn bench_rasterizers(c: &mut Criterion) {
+    let state = RasterState::default();
+    let scenes = [
+        ("far", screen_space_model(&state, Vec3::new(1.0, 1.0, 4.0))),
+        ("near", screen_space_model(&state, Vec3::new(0.3, 0.3, 1.2))),
+    ];
+
+    let mut group = c.benchmark_group("coverage");
+    group.sample_size(20);
+    for (scene, triangles) in &scenes {
+        group.bench_with_input(BenchmarkId::new("barycentric", scene), triangles, |b, triangles| b.iter(|| {
+            let mut pixels = 0;
+            for (screen_coords, _) in triangles {
+                let (bboxmin, bboxmax) = bounding_box(screen_coords, WIDTH, HEIGHT);
+                rasterize_barycentric_coverage(screen_coords, bboxmin, bboxmax, |_, _, _| pixels += 1);
+            }
+            pixels
+        }));
+
+        group.bench_with_input(BenchmarkId::new("edge_function", scene), triangles, |b, triangles| b.iter(|| {
+            let mut pixels = 0;
+            for (screen_coords, _) in triangles {
+                let (bboxmin, bboxmax) = bounding_box(screen_coords, WIDTH, HEIGHT);
+                rasterize_coverage(screen_coords, bboxmin, bboxmax, |_, _, _| pixels += 1);
+            }
+            pixels
+        }));
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("depth");
+    group.sample_size(20);
+    let mut zbuffer = vec![f32::MIN; WIDTH * HEIGHT];
+    for (scene, triangles) in &scenes {
+        group.bench_with_input(BenchmarkId::new("barycentric", scene), triangles, |b, triangles| b.iter(|| {
+            zbuffer.fill(f32::MIN);
+            for (screen_coords, bary_coords) in triangles {
+                let (bboxmin, bboxmax) = bounding_box(screen_coords, WIDTH, HEIGHT);
+                rasterize_barycentric(&state, screen_coords, bary_coords, bboxmin, bboxmax, |p_x, p_y, p_z, _| {
+                    depth_test(&mut zbuffer, p_x + p_y*WIDTH, p_z)
+                });
+            }
+        }));
+
+        group.bench_with_input(BenchmarkId::new("edge_function", scene), triangles, |b, triangles| b.iter(|| {
+            zbuffer.fill(f32::MIN);
+            for (screen_coords, bary_coords) in triangles {
+                let (bboxmin, bboxmax) = bounding_box(screen_coords, WIDTH, HEIGHT);
+                rasterize(&state, screen_coords, bary_coords, bboxmin, bboxmax, |p_x, p_y, p_z, _| {
+                    depth_test(&mut zbuffer, p_x + p_y*WIDTH, p_z)
+                });
+            }
+        }));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_rasterizers);
+criterion_main!(benches);
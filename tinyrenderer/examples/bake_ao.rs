@@ -0,0 +1,22 @@
+use renderer::bake::{bake_ambient_occlusion, AoBakeConfig};
+use renderer::obj::*;
+use std::{env, time};
+
+// Bakes a model's ambient occlusion into `assets/{obj_name}/{obj_name}_ao.tga`, next to its other maps
+// (eg `cargo run --release --example bake_ao -- diablo3_pose`)
+fn main() {
+    let obj_name = env::args().nth(1).unwrap_or(String::from("diablo3_pose"));
+
+    let (mesh, warnings) = parse_obj_lenient(&format!("assets/{obj_name}/{obj_name}.obj")).unwrap();
+    for warning in warnings {
+        eprintln!("warning: {warning}");
+    }
+
+    let config = AoBakeConfig::default();
+    let now = time::Instant::now();
+    let ao_img = bake_ambient_occlusion(&mesh, &config);
+    println!("baked {} directions in {:?}", config.directions, now.elapsed());
+
+    // (rows are top down already, like the other maps)
+    ao_img.write_tga_file(&format!("assets/{obj_name}/{obj_name}_ao.tga"), false, true).unwrap();
+}
@@ -0,0 +1,189 @@
+use crate::tgaimage::*;
+use crate::shaders::Shader;
+use crate::rasterizer::{bounding_box, rasterize, screen_triangles, DrawStats, RasterState};
+use crate::transform::Transform;
+use crate::framebuffer::DepthBuffer;
+use std::fmt::Debug;
+use glam::*;
+
+/// Default cap on fragments per pixel.
+pub const MAX_FRAGMENTS: usize = 8;
+
+/// An A-buffer, for order-independent transparency.
+///
+/// Instead of blending fragments as they arrive (which needs triangles sorted back to front,
+/// and still goes wrong where transparent triangles intersect), every pixel keeps a list of its transparent fragments.
+/// `resolve` then sorts each list by depth and composites it over the (already rendered) opaque image.
+///
+/// Lists are capped at `max_fragments`; past that, the two furthest fragments get merged into one,
+/// which is exact as long as nothing later lands between them (and close enough otherwise, as they're the least visible).
+pub struct ABuffer<T> {
+    pub width: usize,
+    pub height: usize,
+    pub max_fragments: usize,
+    lists: Vec<Vec<Fragment<T>>>, // per pixel, in the order they were drawn
+    merged: usize
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Fragment<T> {
+    depth: f32,
+    color: T,
+    alpha: f32
+}
+
+impl<T: ColorSpace + Copy + Debug> ABuffer<T> {
+    /// Makes an empty `width` x `height` A-buffer, keeping up to MAX_FRAGMENTS fragments per pixel.
+    pub fn new(width: usize, height: usize) -> Self {
+        ABuffer {
+            width,
+            height,
+            max_fragments: MAX_FRAGMENTS,
+            lists: vec![Vec::new(); width * height],
+            merged: 0
+        }
+    }
+
+    /// Like `rasterizer::draw_triangle`, but adds the fragments that pass the depth test against `depth` (ie the opaque geometry's) to the lists.
+    /// The depth buffer is only read from: transparent fragments never hide each other.
+    #[allow(clippy::too_many_arguments)]
+    pub fn draw_triangle<S: Shader<T>>(
+        &mut self,
+        shader: &S,
+        varying: &S::Varying,
+        state: &RasterState,
+        transform: &Transform,
+        clip_coords: [Vec4; 3],
+        depth: &DepthBuffer,
+        stats: &mut DrawStats
+    ) {
+        for (screen_coords, bary_coords) in screen_triangles(state, transform, clip_coords, stats) {
+            let (bboxmin, bboxmax) = bounding_box(&screen_coords, self.width, self.height);
+            rasterize(state, &screen_coords, &bary_coords, bboxmin, bboxmax, |p_x, p_y, p_z, bc_clip| {
+                let idx = p_x + p_y*self.width;
+                if !depth.test(idx, p_z) {
+                    return;
+                }
+
+                let mut color = T::white();
+                if !shader.fragment(varying, bc_clip, &mut color) {
+                    self.insert(idx, Fragment { depth: p_z, color, alpha: color.alpha() });
+                }
+            });
+        }
+    }
+
+    // adds a fragment to a pixel's list, merging the two furthest ones if it's full
+    fn insert(&mut self, idx: usize, fragment: Fragment<T>) {
+        let list = &mut self.lists[idx];
+        list.push(fragment);
+        if list.len() <= self.max_fragments.max(1) {
+            return;
+        }
+
+        // (greater z is closer, so the furthest fragments have the smallest z)
+        list.sort_by(|a, b| a.depth.total_cmp(&b.depth));
+        let furthest = list.remove(0);
+        list[0] = composite(&list[0], &furthest);
+        self.merged += 1;
+    }
+
+    /// How many times a full list had fragments merged, since the last `clear`.
+    pub fn merged(&self) -> usize {
+        self.merged
+    }
+
+    /// Composites every pixel's fragments, back to front, over `image` (ie the opaque geometry).
+    pub fn resolve(&mut self, image: &mut Image<T>) {
+        for (pixel, list) in image.data.iter_mut().zip(&mut self.lists) {
+            list.sort_by(|a, b| a.depth.total_cmp(&b.depth));
+            for fragment in list.iter() {
+                let alpha = fragment.alpha + pixel.alpha() * (1.0 - fragment.alpha); // ie "over"
+                *pixel = mix(&fragment.color, fragment.alpha, pixel, 1.0 - fragment.alpha);
+                pixel.set_alpha(alpha);
+            }
+        }
+    }
+
+    /// Empties every list, so the A-buffer can be reused for another frame.
+    pub fn clear(&mut self) {
+        self.lists.iter_mut().for_each(Vec::clear);
+        self.merged = 0;
+    }
+}
+
+// Composites `far` under `near`, into a fragment at `near`'s depth that looks the same over anything behind both of them.
+fn composite<T: ColorSpace + Copy>(near: &Fragment<T>, far: &Fragment<T>) -> Fragment<T> {
+    let alpha = near.alpha + far.alpha * (1.0 - near.alpha);
+    if alpha <= 0.0 {
+        return *near;
+    }
+
+    // colors are straight (ie not premultiplied), so weigh them by their contribution, then divide the total alpha back out
+    let mut color = mix(&near.color, near.alpha / alpha, &far.color, far.alpha * (1.0 - near.alpha) / alpha);
+    color.set_alpha(alpha);
+    Fragment { depth: near.depth, color, alpha }
+}
+
+// a*weight_a + b*weight_b, channel by channel
+fn mix<T: ColorSpace>(a: &T, weight_a: f32, b: &T, weight_b: f32) -> T {
+    let channels = a.to_vec()
+        .into_iter()
+        .zip(b.to_vec())
+        .map(|(a, b)| (a as f32 * weight_a + b as f32 * weight_b).round().clamp(0.0, 255.0) as u8)
+        .collect();
+
+    let mut color = T::new();
+    color.from_vec(channels).unwrap();
+    color
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // resolves fragments (given as (depth, color)) drawn into one pixel, over opaque black
+    fn resolve_pixel(max_fragments: usize, fragments: &[(f32, RGBA)]) -> (Vec<u8>, usize) {
+        let mut abuffer = ABuffer::new(1, 1);
+        abuffer.max_fragments = max_fragments;
+        for &(depth, color) in fragments {
+            abuffer.insert(0, Fragment { depth, color, alpha: color.alpha() });
+        }
+        assert!(abuffer.lists[0].len() <= max_fragments);
+
+        let mut image: Image<RGBA> = Image::new(1, 1);
+        image.data[0] = RGBA { r: 0, g: 0, b: 0, a: 255 };
+        abuffer.resolve(&mut image);
+        (image.data[0].to_vec(), abuffer.merged())
+    }
+
+    #[test]
+    fn merges_past_max_fragments() {
+        // drawn far to near, so merging the furthest two is exact (up to rounding)
+        let fragments = [
+            (0.1, RGBA { r: 255, g: 0, b: 0, a: 128 }),
+            (0.2, RGBA { r: 0, g: 255, b: 0, a: 128 }),
+            (0.3, RGBA { r: 0, g: 0, b: 255, a: 64 }),
+            (0.4, RGBA { r: 255, g: 255, b: 255, a: 192 })
+        ];
+        let (exact, merged) = resolve_pixel(MAX_FRAGMENTS, &fragments);
+        assert_eq!(merged, 0);
+        assert_eq!(exact, vec![204, 216, 208, 255]);
+
+        let (capped, merged) = resolve_pixel(2, &fragments);
+        assert_eq!(merged, 2);
+        for (capped, exact) in capped.iter().zip(&exact) {
+            assert!(capped.abs_diff(*exact) <= 1, "{capped:?} vs {exact:?}");
+        }
+    }
+
+    #[test]
+    fn resolves_in_depth_order() {
+        // the same fragments, in any order, end up composited back to front
+        let (near, far) = ((0.5, RGBA { r: 255, g: 0, b: 0, a: 128 }), (0.1, RGBA { r: 0, g: 0, b: 255, a: 255 }));
+        let (in_order, _) = resolve_pixel(MAX_FRAGMENTS, &[far, near]);
+        let (reversed, _) = resolve_pixel(MAX_FRAGMENTS, &[near, far]);
+        assert_eq!(in_order, vec![128, 0, 127, 255]);
+        assert_eq!(reversed, in_order);
+    }
+}
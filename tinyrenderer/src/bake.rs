@@ -0,0 +1,135 @@
+use crate::tgaimage::*;
+use crate::shaders::{AoBakeShader, DepthShader, Shader};
+use crate::rasterizer::{draw_triangle, triangle, CullMode, DrawStats, RasterState};
+use crate::framebuffer::{CompareFunc, Framebuffer};
+use crate::transform::{initialize_transform, Projection};
+use crate::obj::Mesh;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use glam::*;
+
+/// Ambient occlusion baking settings.
+#[derive(Clone, Copy, Debug)]
+pub struct AoBakeConfig {
+    pub width: usize,          // of the baked texture
+    pub height: usize,
+    pub directions: usize,     // how many directions (on the sphere around the model) to look from
+    pub depth_map_size: usize, // of each direction's depth map
+    pub bias: f32,             // in depth map z-units
+    pub dilation: usize        // how many texels to grow the baked areas by, so bilinear/mip lookups don't bleed in black at uv seams
+}
+
+impl Default for AoBakeConfig {
+    fn default() -> Self {
+        AoBakeConfig {
+            width: 1024,
+            height: 1024,
+            directions: 256,
+            depth_map_size: 1024,
+            bias: 1.0,
+            dilation: 4
+        }
+    }
+}
+
+/// Bakes ambient occlusion into a texture, for the mesh's uv layout (eg to ship as `*_ao.tga`, next to `*_diffuse.tga`).
+///
+/// The model's depth gets rendered from many random directions around it (with `DepthShader`). For each one,
+/// every texel that faces the direction checks whether its point on the model is visible in that depth map.
+/// A texel's value is then the fraction of its hemisphere it was visible from, ie white where nothing's in the way.
+/// Rows go top down, like images that are read in, so write it with `vflip` unset.
+pub fn bake_ambient_occlusion(mesh: &Mesh, config: &AoBakeConfig) -> Image<Grayscale> {
+    let texels = config.width * config.height;
+    let (mut visible, mut counted) = (vec![0u32; texels], vec![0u32; texels]);
+
+    // fit the model in a sphere, for the depth maps' orthographic projections to capture
+    let (min, max) = mesh.positions.iter().fold((Vec3::MAX, Vec3::MIN), |(min, max), &p| (min.min(p), max.max(p)));
+    let centre = (min + max) / 2.0;
+    let radius = mesh.positions.iter().map(|&p| (p - centre).length()).fold(f32::EPSILON, f32::max);
+    let projection = Projection::Orthographic {
+        left: -radius, right: radius, bottom: -radius, top: radius,
+        near: radius, far: 3.0 * radius
+    };
+
+    // front faces are culled, so surfaces aren't compared against their own depth (see main)
+    let depth_state = RasterState { cull_mode: CullMode::Front, ..RasterState::default() };
+    let bake_state = RasterState { cull_mode: CullMode::None, depth_write: false, ..RasterState::default() };
+    let mut stats = DrawStats::default();
+
+    // seeded, so baking the same model gives the same texture
+    let mut rng = StdRng::seed_from_u64(0);
+    for _ in 0..config.directions {
+        let dir = random_direction(&mut rng);
+        let up = if dir.y.abs() > 0.99 { Vec3::X } else { Vec3::Y };
+        let depth_transform = initialize_transform(config.depth_map_size, config.depth_map_size, centre + dir * 2.0 * radius, centre, up, projection);
+
+        // the model's depth, from this direction
+        let mut depth_framebuffer: Framebuffer<Grayscale> = Framebuffer::new(config.depth_map_size, config.depth_map_size, 1);
+        let mut depth_shader = DepthShader::new(depth_transform);
+        for obj_face in mesh {
+            let (clip_coords, varying) = Shader::<Grayscale>::vertex(&mut depth_shader, obj_face);
+            draw_triangle(&mut depth_framebuffer, &depth_shader, &varying, &depth_state, &depth_transform, clip_coords, &mut stats);
+        }
+
+        // then which texels can see it (faces sharing texels, eg with mirrored uvs, just overwrite each other)
+        let mut bake_framebuffer: Framebuffer<Grayscale> = Framebuffer::new(config.width, config.height, 2);
+        bake_framebuffer.depth.compare = CompareFunc::Always;
+        let mut bake_shader = AoBakeShader::new(&depth_transform, depth_framebuffer.depth, config.width, config.height, config.bias, dir);
+        for obj_face in mesh {
+            let (texture_coords, varying) = bake_shader.vertex(obj_face);
+            triangle(&mut bake_framebuffer, &bake_shader, &varying, &bake_state, texture_coords, [Vec3::X, Vec3::Y, Vec3::Z]);
+        }
+
+        for (i, (visibility, count)) in bake_framebuffer.colors[0].data.iter().zip(&bake_framebuffer.colors[1].data).enumerate() {
+            visible[i] += u32::from(visibility.i > 0);
+            counted[i] += u32::from(count.i > 0);
+        }
+    }
+
+    let mut occlusion: Vec<Option<f32>> = visible
+        .iter()
+        .zip(&counted)
+        .map(|(&visible, &counted)| (counted > 0).then(|| visible as f32 / counted as f32))
+        .collect();
+    for _ in 0..config.dilation {
+        occlusion = dilate(&occlusion, config.width, config.height);
+    }
+
+    let mut image = Image::new(config.width, config.height);
+    for (pixel, ao) in image.data.iter_mut().zip(occlusion) {
+        // (texels no face covers are never looked up, so they may as well be unoccluded)
+        *pixel = Grayscale { i: (ao.unwrap_or(1.0) * 255.0).round() as u8 };
+    }
+    image
+}
+
+// uniformly distributed over the unit sphere
+fn random_direction(rng: &mut StdRng) -> Vec3 {
+    let z: f32 = rng.gen_range(-1.0..1.0);
+    let phi = rng.gen_range(0.0..std::f32::consts::TAU);
+    let r = (1.0 - z * z).sqrt();
+    Vec3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+// fills empty texels next to filled ones with the average of those neighbours
+fn dilate(texels: &[Option<f32>], width: usize, height: usize) -> Vec<Option<f32>> {
+    let mut dilated = texels.to_vec();
+    for (idx, texel) in dilated.iter_mut().enumerate() {
+        if texel.is_some() {
+            continue;
+        }
+        let (x, y) = (idx % width, idx / width);
+        let neighbours: Vec<f32> = [(-1, 0), (1, 0), (0, -1), (0, 1)]
+            .iter()
+            .filter_map(|&(dx, dy)| {
+                let (nx, ny) = (x as i32 + dx, y as i32 + dy);
+                (nx >= 0 && ny >= 0 && (nx as usize) < width && (ny as usize) < height)
+                    .then(|| texels[nx as usize + ny as usize * width])
+                    .flatten()
+            })
+            .collect();
+        if !neighbours.is_empty() {
+            *texel = Some(neighbours.iter().sum::<f32>() / neighbours.len() as f32);
+        }
+    }
+    dilated
+}
@@ -0,0 +1,93 @@
+use glam::*;
+
+/// How far out (in multiples of w) x and y may go before triangles get clipped against the sides of the frustum.
+/// Anything off-screen but within the guard band is left to the rasterizer's bounding box clamp,
+/// which is much cheaper than splitting the triangle.
+pub const GUARD_BAND: f32 = 4.0;
+
+/// A vertex of a clipped polygon, in clip space.
+/// `bary` is its barycentric coordinates w.r.t. the original triangle (ie the one the shader's varyings belong to).
+#[derive(Clone, Copy, Debug)]
+pub struct ClipVertex {
+    pub position: Vec4,
+    pub bary: Vec3
+}
+
+// Planes are stored as Vec4s, such that a point p is inside if plane.dot(p) >= 0.
+// (in clip space, the frustum is -w <= x, y, z <= w)
+const NEAR_PLANE: Vec4 = Vec4::new(0.0, 0.0, 1.0, 1.0);
+const FAR_PLANE: Vec4 = Vec4::new(0.0, 0.0, -1.0, 1.0);
+
+fn side_planes(guard_band: f32) -> [Vec4; 4] {
+    [
+        Vec4::new(1.0, 0.0, 0.0, guard_band), // left
+        Vec4::new(-1.0, 0.0, 0.0, guard_band), // right
+        Vec4::new(0.0, 1.0, 0.0, guard_band), // bottom
+        Vec4::new(0.0, -1.0, 0.0, guard_band), // top
+    ]
+}
+
+/// Clips a triangle in clip space against the near and far planes,
+/// and against the sides of the frustum (scaled by `guard_band`) if one is given.
+/// Returns the triangles the clipped polygon is split into (ie none if it's entirely outside).
+pub fn clip_triangle(clip_coords: [Vec4; 3], guard_band: Option<f32>) -> Vec<[ClipVertex; 3]> {
+    let mut planes = vec![NEAR_PLANE, FAR_PLANE];
+    if let Some(guard_band) = guard_band {
+        planes.extend(side_planes(guard_band));
+    }
+
+    let original = [
+        ClipVertex { position: clip_coords[0], bary: Vec3::X },
+        ClipVertex { position: clip_coords[1], bary: Vec3::Y },
+        ClipVertex { position: clip_coords[2], bary: Vec3::Z },
+    ];
+
+    // trivially accept/reject if all vertices are on the same side of every plane
+    if planes.iter().all(|plane| clip_coords.iter().all(|v| plane.dot(*v) >= 0.0)) {
+        return vec![original];
+    }
+    if planes.iter().any(|plane| clip_coords.iter().all(|v| plane.dot(*v) < 0.0)) {
+        return Vec::new();
+    }
+
+    // otherwise, clip the polygon against each plane in turn (Sutherland-Hodgman)
+    let mut polygon = original.to_vec();
+    for plane in planes {
+        polygon = clip_polygon(&polygon, plane);
+        if polygon.len() < 3 {
+            return Vec::new();
+        }
+    }
+
+    // then split it back up into a triangle fan
+    (1..polygon.len() - 1)
+        .map(|i| [polygon[0], polygon[i], polygon[i + 1]])
+        .collect()
+}
+
+// Clip a convex polygon against a single plane, keeping the part in front of it.
+fn clip_polygon(polygon: &[ClipVertex], plane: Vec4) -> Vec<ClipVertex> {
+    let mut clipped = Vec::with_capacity(polygon.len() + 1);
+
+    for i in 0..polygon.len() {
+        let current = polygon[i];
+        let next = polygon[(i + 1) % polygon.len()];
+        let current_dist = plane.dot(current.position);
+        let next_dist = plane.dot(next.position);
+
+        if current_dist >= 0.0 {
+            clipped.push(current);
+        }
+
+        // edge crosses the plane, so add the intersection
+        if (current_dist >= 0.0) != (next_dist >= 0.0) {
+            let t = current_dist / (current_dist - next_dist);
+            clipped.push(ClipVertex {
+                position: current.position.lerp(next.position, t),
+                bary: current.bary.lerp(next.bary, t)
+            });
+        }
+    }
+
+    clipped
+}
@@ -0,0 +1,192 @@
+use crate::tgaimage::*;
+use crate::shaders::{GeometryShader, LightingShader};
+use crate::rasterizer::{bounding_box, rasterize, screen_triangles, DrawStats, RasterState};
+use crate::transform::Transform;
+use crate::framebuffer::DepthBuffer;
+use std::fmt::Debug;
+use glam::*;
+
+/// What the geometry pass knows about the surface at a pixel: everything lighting needs, but no lighting yet.
+#[derive(Clone, Copy, Debug)]
+pub struct Surface<T> {
+    pub position: Vec3, // in view space
+    pub normal: Vec3,   // in view space, normalized
+    pub geometric_normal: Vec3, // the interpolated vertex normal (ie without normal mapping), in view space, normalized (eg for shadow biases)
+    pub albedo: T,
+    pub specular: f32,  // specular exponent
+    pub specular_weight: f32, // how much specular highlights count
+    pub ambient: Vec3,  // the ambient light's color (see `Model::get_ambient`)
+    pub occlusion: f32, // baked ambient occlusion, in [0, 1]
+    pub material: Option<usize>
+}
+
+/// The G-buffer's channels, eg to dump as debug images.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GChannel {
+    Position,
+    Normal,
+    Albedo,
+    Specular,
+    Material,
+    Depth
+}
+
+impl GChannel {
+    pub const ALL: [GChannel; 6] = [GChannel::Position, GChannel::Normal, GChannel::Albedo, GChannel::Specular, GChannel::Material, GChannel::Depth];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            GChannel::Position => "position",
+            GChannel::Normal => "normal",
+            GChannel::Albedo => "albedo",
+            GChannel::Specular => "specular",
+            GChannel::Material => "material",
+            GChannel::Depth => "depth"
+        }
+    }
+}
+
+/// A G-buffer, for deferred shading.
+///
+/// Forward shading does texture fetches + lighting for every fragment that passes the depth test,
+/// even those that get drawn over later on. Instead, the geometry pass (see `GeometryShader`) only keeps
+/// the closest surface at each pixel, and `shade` then lights each pixel once (see `LightingShader`).
+pub struct GBuffer<T> {
+    pub width: usize,
+    pub height: usize,
+    surfaces: Vec<Option<Surface<T>>>, // None where nothing was drawn
+    pub depth: DepthBuffer
+}
+
+impl<T: ColorSpace + Copy + Debug> GBuffer<T> {
+    /// Makes an empty `width` x `height` G-buffer.
+    pub fn new(width: usize, height: usize) -> Self {
+        GBuffer {
+            width,
+            height,
+            surfaces: vec![None; width * height],
+            depth: DepthBuffer::new(width, height)
+        }
+    }
+
+    /// Like `rasterizer::draw_triangle`, but stores the geometry shader's surface at the pixels that pass the depth test.
+    pub fn draw_triangle<S: GeometryShader<T>>(
+        &mut self,
+        shader: &S,
+        varying: &S::Varying,
+        state: &RasterState,
+        transform: &Transform,
+        clip_coords: [Vec4; 3],
+        stats: &mut DrawStats
+    ) {
+        for (screen_coords, bary_coords) in screen_triangles(state, transform, clip_coords, stats) {
+            let (bboxmin, bboxmax) = bounding_box(&screen_coords, self.width, self.height);
+            rasterize(state, &screen_coords, &bary_coords, bboxmin, bboxmax, |p_x, p_y, p_z, bc_clip| {
+                let idx = p_x + p_y*self.width;
+                if !self.depth.test(idx, p_z) {
+                    return;
+                }
+
+                if let Some(surface) = shader.fragment(varying, bc_clip) {
+                    if state.depth_write {
+                        self.depth.data[idx] = p_z;
+                    }
+                    self.surfaces[idx] = Some(surface);
+                }
+            });
+        }
+    }
+
+    /// The surface at a pixel, or None if nothing was drawn there (or it's outside the buffer).
+    pub fn surface(&self, x: usize, y: usize) -> Option<&Surface<T>> {
+        (x < self.width && y < self.height).then(|| self.surfaces[x + y*self.width].as_ref()).flatten()
+    }
+
+    /// Every pixel's normal (in view space), eg for SSAO; pixels without a surface get Vec3::ZERO.
+    pub fn normals(&self) -> Vec<Vec3> {
+        self.surfaces.iter().map(|surface| surface.map_or(Vec3::ZERO, |surface| surface.normal)).collect()
+    }
+
+    /// Lights every pixel that has a surface, into `image` (pixels without one are left alone).
+    pub fn shade<L: LightingShader<T>>(&self, shader: &L, image: &mut Image<T>) {
+        for (pixel, surface) in image.data.iter_mut().zip(&self.surfaces) {
+            if let Some(surface) = surface {
+                let mut color = T::white();
+                shader.shade(surface, &mut color);
+                *pixel = color;
+            }
+        }
+    }
+
+    /// Visualizes a channel, for debugging:
+    /// positions + depth are stretched over their range, normals go from [-1, 1] to [0, 255],
+    /// specular exponents are scaled by the largest one, and each material gets its own color.
+    pub fn channel_image(&self, channel: GChannel) -> Image<RGB> {
+        let mut image: Image<RGB> = Image::new(self.width, self.height);
+        let drawn = || self.surfaces.iter().flatten();
+
+        // ranges to normalize by
+        let (min_position, max_position) = drawn().fold((Vec3::MAX, Vec3::MIN), |(min, max), surface| {
+            (min.min(surface.position), max.max(surface.position))
+        });
+        let max_specular = drawn().fold(f32::EPSILON, |max, surface| max.max(surface.specular));
+        let (min_depth, max_depth) = self.depth.data
+            .iter()
+            .zip(&self.surfaces)
+            .filter(|(_, surface)| surface.is_some())
+            .fold((f32::MAX, f32::MIN), |(min, max), (&z, _)| (min.min(z), max.max(z)));
+
+        for ((pixel, surface), &z) in image.data.iter_mut().zip(&self.surfaces).zip(&self.depth.data) {
+            let Some(surface) = surface else { continue };
+            *pixel = match channel {
+                GChannel::Position => to_rgb((surface.position - min_position) / (max_position - min_position).max(Vec3::splat(f32::EPSILON))),
+                GChannel::Normal => to_rgb(surface.normal * 0.5 + 0.5),
+                GChannel::Albedo => {
+                    let channels = surface.albedo.to_vec();
+                    match channels[..] {
+                        [i] => RGB { r: i, g: i, b: i },
+                        [r, g, b, ..] => RGB { r, g, b },
+                        _ => RGB::white()
+                    }
+                },
+                GChannel::Specular => to_rgb(Vec3::splat(surface.specular / max_specular)),
+                GChannel::Material => material_color(surface.material),
+                GChannel::Depth => to_rgb(Vec3::splat((z - min_depth) / (max_depth - min_depth).max(f32::EPSILON)))
+            };
+        }
+        image
+    }
+
+    /// Empties the G-buffer, so it can be reused for another frame.
+    pub fn clear(&mut self) {
+        self.surfaces.fill(None);
+        self.depth.clear();
+    }
+}
+
+// [0, 1] per channel -> a color
+fn to_rgb(v: Vec3) -> RGB {
+    let v = (v.clamp(Vec3::ZERO, Vec3::ONE) * 255.0).round();
+    RGB { r: v.x as u8, g: v.y as u8, b: v.z as u8 }
+}
+
+// a distinct(ish) color per material id, with grey for faces without a material
+fn material_color(material: Option<usize>) -> RGB {
+    match material {
+        None => RGB { r: 128, g: 128, b: 128 },
+        Some(id) => {
+            // spread hues out by the golden ratio, so neighbouring ids look different
+            let hue = (id as f32 * 0.618_034).fract() * 6.0;
+            let x = 1.0 - (hue % 2.0 - 1.0).abs();
+            let (r, g, b) = match hue as usize {
+                0 => (1.0, x, 0.0),
+                1 => (x, 1.0, 0.0),
+                2 => (0.0, 1.0, x),
+                3 => (0.0, x, 1.0),
+                4 => (x, 0.0, 1.0),
+                _ => (1.0, 0.0, x)
+            };
+            to_rgb(Vec3::new(r, g, b))
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use crate::tgaimage::*;
+use glam::*;
+use std::io;
+
+/// The most color targets a framebuffer can have.
+pub const MAX_COLOR_TARGETS: usize = 8;
+
+/// How a value (eg a fragment's depth) gets compared against what's stored; it passes if `value <op> stored`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompareFunc {
+    Never,
+    Less,
+    LessEqual,
+    Equal,
+    NotEqual,
+    GreaterEqual,
+    Greater,
+    Always
+}
+
+impl CompareFunc {
+    pub fn passes<V: PartialOrd>(self, value: V, stored: V) -> bool {
+        match self {
+            CompareFunc::Never => false,
+            CompareFunc::Less => value < stored,
+            CompareFunc::LessEqual => value <= stored,
+            CompareFunc::Equal => value == stored,
+            CompareFunc::NotEqual => value != stored,
+            CompareFunc::GreaterEqual => value >= stored,
+            CompareFunc::Greater => value > stored,
+            CompareFunc::Always => true
+        }
+    }
+
+    /// The same comparison, but also passing equal values (eg to redraw geometry over itself).
+    pub fn or_equal(self) -> Self {
+        match self {
+            CompareFunc::Never => CompareFunc::Equal,
+            CompareFunc::Less => CompareFunc::LessEqual,
+            CompareFunc::Greater => CompareFunc::GreaterEqual,
+            CompareFunc::NotEqual => CompareFunc::Always,
+            other => other
+        }
+    }
+}
+
+/// A depth attachment.
+/// By default, greater z is closer (the viewport flips it), so fragments pass if they're greater, and it's cleared to f32::MIN.
+#[derive(Clone, Debug)]
+pub struct DepthBuffer {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<f32>,
+    pub compare: CompareFunc,
+    pub clear_value: f32
+}
+
+impl DepthBuffer {
+    pub fn new(width: usize, height: usize) -> Self {
+        DepthBuffer {
+            width,
+            height,
+            data: vec![f32::MIN; width * height],
+            compare: CompareFunc::Greater,
+            clear_value: f32::MIN
+        }
+    }
+
+    /// The depth at a pixel, or None if it's outside the buffer.
+    pub fn get(&self, x: usize, y: usize) -> Option<f32> {
+        (x < self.width && y < self.height).then(|| self.data[x + y*self.width])
+    }
+
+    /// Whether a fragment with depth `z` passes the depth test at pixel `idx`.
+    pub fn test(&self, idx: usize, z: f32) -> bool {
+        self.compare.passes(z, self.data[idx])
+    }
+
+    pub fn clear(&mut self) {
+        self.data.fill(self.clear_value);
+    }
+
+    /// Writes the depths as a grayscale PFM (see `write_pfm_file`), with cleared pixels (ie nothing drawn) as 0.
+    /// `vflip` is as in `Image::write_tga_file`.
+    pub fn write_pfm_file(&self, filename: &str, vflip: bool) -> io::Result<()> {
+        let data: Vec<f32> = self.data
+            .iter()
+            .map(|&depth| if depth == self.clear_value { 0.0 } else { depth })
+            .collect();
+        write_pfm_file(filename, self.width, self.height, &data, vflip)
+    }
+}
+
+/// An 8-bit stencil attachment.
+#[derive(Clone, Debug)]
+pub struct StencilBuffer {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u8>,
+    pub clear_value: u8
+}
+
+impl StencilBuffer {
+    pub fn new(width: usize, height: usize) -> Self {
+        StencilBuffer {
+            width,
+            height,
+            data: vec![0; width * height],
+            clear_value: 0
+        }
+    }
+
+    /// The stencil value at a pixel, or None if it's outside the buffer.
+    pub fn get(&self, x: usize, y: usize) -> Option<u8> {
+        (x < self.width && y < self.height).then(|| self.data[x + y*self.width])
+    }
+
+    pub fn clear(&mut self) {
+        self.data.fill(self.clear_value);
+    }
+}
+
+/// What the rasterizer renders into: any number of color targets (all the same size), a depth buffer, and a stencil buffer.
+///
+/// The fragment shader's color goes to every target, unless the shader writes each one itself (see `Shader::fragment_targets`),
+/// which allows rendering several images (eg normals + albedo) in one pass.
+#[derive(Clone)]
+pub struct Framebuffer<T: ColorSpace + Copy> {
+    pub width: usize,
+    pub height: usize,
+    pub colors: Vec<Image<T>>,
+    pub depth: DepthBuffer,
+    pub stencil: StencilBuffer
+}
+
+impl<T: ColorSpace + Copy> Framebuffer<T> {
+    /// Makes a `width` x `height` framebuffer with `color_targets` color targets (up to MAX_COLOR_TARGETS), all cleared.
+    pub fn new(width: usize, height: usize, color_targets: usize) -> Self {
+        assert!(color_targets <= MAX_COLOR_TARGETS, "at most {MAX_COLOR_TARGETS} color targets");
+        Framebuffer {
+            width,
+            height,
+            colors: (0..color_targets).map(|_| Image::new(width, height)).collect(),
+            depth: DepthBuffer::new(width, height),
+            stencil: StencilBuffer::new(width, height)
+        }
+    }
+
+    /// Resets the color targets to `T::new()`, and depth + stencil to their clear values.
+    pub fn clear(&mut self) {
+        for color in &mut self.colors {
+            color.data.fill(T::new());
+        }
+        self.depth.clear();
+        self.stencil.clear();
+    }
+
+    /// Copies the (inclusive) region [min, max] into its own framebuffer, eg for a tile to render into.
+    pub fn region(&self, min: UVec2, max: UVec2) -> Framebuffer<T> {
+        let (width, height) = ((max.x - min.x + 1) as usize, (max.y - min.y + 1) as usize);
+
+        Framebuffer {
+            width,
+            height,
+            colors: self.colors
+                .iter()
+                .map(|image| Image { width, height, data: copy_rows(&image.data, self.width, min, width, height) })
+                .collect(),
+            depth: DepthBuffer {
+                width,
+                height,
+                data: copy_rows(&self.depth.data, self.width, min, width, height),
+                compare: self.depth.compare,
+                clear_value: self.depth.clear_value
+            },
+            stencil: StencilBuffer {
+                width,
+                height,
+                data: copy_rows(&self.stencil.data, self.width, min, width, height),
+                clear_value: self.stencil.clear_value
+            }
+        }
+    }
+
+    /// Copies a region (see `region`) back in, with its bottom-left corner at `min`.
+    pub fn write_region(&mut self, region: &Framebuffer<T>, min: UVec2) {
+        let stride = self.width;
+        for row in 0..region.height {
+            let start = min.x as usize + (min.y as usize + row)*stride;
+            let src = row*region.width..(row + 1)*region.width;
+            for (image, region_image) in self.colors.iter_mut().zip(&region.colors) {
+                image.data[start..start + region.width].copy_from_slice(&region_image.data[src.clone()]);
+            }
+            self.depth.data[start..start + region.width].copy_from_slice(&region.depth.data[src.clone()]);
+            self.stencil.data[start..start + region.width].copy_from_slice(&region.stencil.data[src]);
+        }
+    }
+}
+
+// copies a `width` x `height` block of rows, starting at `min`, out of a buffer `stride` values wide
+fn copy_rows<V: Copy>(data: &[V], stride: usize, min: UVec2, width: usize, height: usize) -> Vec<V> {
+    (min.y as usize..min.y as usize + height)
+        .flat_map(|y| &data[min.x as usize + y*stride..min.x as usize + y*stride + width])
+        .copied()
+        .collect()
+}
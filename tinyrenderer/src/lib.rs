@@ -0,0 +1,27 @@
+pub mod tgaimage;
+pub mod line;
+pub mod obj;
+pub mod rasterizer;
+pub mod shaders;
+pub mod transform;
+pub mod clipping;
+pub mod png;
+pub mod model;
+pub mod mtl;
+pub mod tiled;
+pub mod msaa;
+pub mod abuffer;
+pub mod framebuffer;
+pub mod outline;
+pub mod deferred;
+pub mod ssao;
+pub mod bake;
+pub mod shadows;
+pub mod light;
+
+// the modules refer to each other's main types from the crate root
+use crate::shaders::*;
+use crate::tgaimage::*;
+use crate::obj::*;
+use crate::transform::*;
+use crate::model::*;
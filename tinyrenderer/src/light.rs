@@ -0,0 +1,175 @@
+use crate::tgaimage::*;
+use crate::transform::Transform;
+use crate::shadows::{CubeShadowMap, ShadowSettings};
+use crate::ssao::ambient_occlusion;
+use glam::*;
+
+/// How a light fades with distance: by 1 / (constant + linear*d + quadratic*d^2), like the opengl crate's lights.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Attenuation {
+    pub constant: f32,
+    pub linear: f32,
+    pub quadratic: f32
+}
+
+impl Default for Attenuation {
+    // no fading at all
+    fn default() -> Self {
+        Attenuation { constant: 1.0, linear: 0.0, quadratic: 0.0 }
+    }
+}
+
+impl Attenuation {
+    pub fn factor(&self, distance: f32) -> f32 {
+        1.0 / (self.constant + self.linear * distance + self.quadratic * distance * distance)
+    }
+}
+
+/// A light source, matching the opengl crate's `lighting.frag`.
+/// Colors are linear, in [0, 1] per channel, and get scaled by `intensity`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Light {
+    /// Infinitely far away (eg the sun), so its light comes from the same direction everywhere. `direction` points towards the light.
+    Directional { direction: Vec3, color: Vec3, intensity: f32 },
+    /// Shines equally in every direction from `position` (eg a bulb or a torch).
+    Point { position: Vec3, color: Vec3, intensity: f32, attenuation: Attenuation },
+    /// Shines from `position` along `direction`, in a cone: fully inside `inner_angle`, fading out smoothly until `outer_angle` (both in degrees).
+    Spot { position: Vec3, direction: Vec3, color: Vec3, intensity: f32, inner_angle: f32, outer_angle: f32, attenuation: Attenuation }
+}
+
+impl Light {
+    /// A white directional light, eg to light a model from `direction`.
+    pub fn directional(direction: Vec3) -> Self {
+        Light::Directional { direction, color: Vec3::ONE, intensity: 1.0 }
+    }
+
+    /// The same light, moved into view space (ie along with everything else, by the transform's model_view).
+    pub fn view_transform(&self, transform: &Transform) -> Self {
+        match *self {
+            Light::Directional { direction, color, intensity } => Light::Directional {
+                direction: transform.view_dir_transform(direction),
+                color,
+                intensity
+            },
+            Light::Point { position, color, intensity, attenuation } => Light::Point {
+                position: transform.view_transform(position),
+                color,
+                intensity,
+                attenuation
+            },
+            Light::Spot { position, direction, color, intensity, inner_angle, outer_angle, attenuation } => Light::Spot {
+                position: transform.view_transform(position),
+                direction: transform.view_dir_transform(direction),
+                color,
+                intensity,
+                inner_angle,
+                outer_angle,
+                attenuation
+            }
+        }
+    }
+
+    /// The (normalized) direction from `point` towards the light, and how much of its light reaches `point`,
+    /// ie color * intensity, faded by distance + the spot cone.
+    pub fn incident(&self, point: Vec3) -> (Vec3, Vec3) {
+        match *self {
+            Light::Directional { direction, color, intensity } => (direction.normalize_or_zero(), color * intensity),
+            Light::Point { position, color, intensity, attenuation } => {
+                let to_light = position - point;
+                (to_light.normalize_or_zero(), color * intensity * attenuation.factor(to_light.length()))
+            },
+            Light::Spot { position, direction, color, intensity, inner_angle, outer_angle, attenuation } => {
+                let to_light = position - point;
+                let to_light_dir = to_light.normalize_or_zero();
+
+                // how far into the cone the point is
+                let (inner_cos, outer_cos) = (inner_angle.to_radians().cos(), outer_angle.to_radians().cos());
+                let theta = (-to_light_dir).dot(direction.normalize_or_zero());
+                let cone = smoothstep((theta - outer_cos) / (inner_cos - outer_cos).max(f32::EPSILON));
+
+                (to_light_dir, color * intensity * attenuation.factor(to_light.length()) * cone)
+            }
+        }
+    }
+}
+
+/// Which lights cast shadows, and how: each one's cube shadow maps (in object space), by index into the lights.
+/// Lighting shaders scale `Light::incident`'s radiance by `visibility`, so they all shadow the same lights the same way.
+#[derive(Clone, Default)]
+pub struct LightShadows {
+    pub settings: ShadowSettings,
+    pub cube_maps: Vec<(usize, CubeShadowMap)>
+}
+
+impl LightShadows {
+    /// Makes `lights[light]` cast shadows with `shadow_map` (rendered from its position, in object space).
+    pub fn add_cube_map(&mut self, light: usize, shadow_map: CubeShadowMap) {
+        self.cube_maps.push((light, shadow_map));
+    }
+
+    /// How much of `lights[light]`'s light reaches `point`, from `settings.min_light` (fully in shadow) to 1 (or 1 if it has no shadow maps).
+    /// `point` + its geometric, normalized `normal` are in object space.
+    pub fn visibility(&self, light: usize, point: Vec3, normal: Vec3) -> f32 {
+        self.cube_maps
+            .iter()
+            .filter(|(i, _)| *i == light)
+            .fold(1.0, |shadow, (_, shadow_map)| shadow * self.settings.shade(shadow_map.visibility(&self.settings, point, normal)))
+    }
+}
+
+/// What keeps light from reaching a surface: the lights' shadows, and ambient occlusion (eg from SSAO) over the ambient light.
+/// Lighting shaders keep one as a uniform (see `shaders::OccludedShader`).
+#[derive(Clone, Default)]
+pub struct Occlusion {
+    pub shadows: LightShadows,
+    pub ambient: Option<Image<Grayscale>>, // scales the ambient term, per pixel
+    pub to_screen: Mat4 // takes points into `ambient`'s screen space (eg the whole transform), so it's only worked out once
+}
+
+impl Occlusion {
+    pub fn new(to_screen: Mat4) -> Self {
+        Occlusion { to_screen, ..Default::default() }
+    }
+
+    /// The ambient occlusion at `point` (in whichever space `to_screen` takes), or 1 without an occlusion image.
+    pub fn ambient_occlusion(&self, point: Vec3) -> f32 {
+        self.ambient.as_ref().map_or(1.0, |occlusion| ambient_occlusion(occlusion, self.to_screen.project_point3(point)))
+    }
+}
+
+/// Sums up the light reflected at `position` (in view space): each light's radiance, times how much of it isn't shadowed
+/// (`visibility(i)` for `lights[i]`), times how much of it gets reflected, given the direction towards it (`reflect(light_dir)`).
+pub fn accumulate_light(lights: &[Light], position: Vec3, visibility: impl Fn(usize) -> f32, reflect: impl Fn(Vec3) -> Vec3) -> Vec3 {
+    lights.iter().enumerate().fold(Vec3::ZERO, |total, (i, light)| {
+        let (light_dir, radiance) = light.incident(position);
+        total + radiance * visibility(i) * reflect(light_dir)
+    })
+}
+
+/// Like `accumulate_light`, for unshadowed Lambertian diffuse light, ie each light's radiance times n.l.
+pub fn accumulate_diffuse(lights: &[Light], position: Vec3, normal: Vec3) -> Vec3 {
+    accumulate_light(lights, position, |_| 1.0, |light_dir| Vec3::splat(f32::max(0.0, normal.dot(light_dir))))
+}
+
+/// Lights a color channel by channel: each of r, g + b becomes `ambient + c * light` (in that channel), capped at 255.
+/// Grayscale gets the mean of the ambient + light's channels; alpha isn't touched.
+pub fn apply_light<T: ColorSpace>(color: &mut T, ambient: Vec3, light: Vec3) {
+    let mut color_vec = color.to_vec();
+    if color_vec.len() < 3 {
+        let (ambient, light) = (ambient.dot(Vec3::ONE) / 3.0, light.dot(Vec3::ONE) / 3.0);
+        for c in color_vec.iter_mut() {
+            *c = f32::min(ambient + (*c as f32)*light, 255.0) as u8;
+        }
+    } else {
+        for ((c, ambient), light) in color_vec.iter_mut().zip(ambient.to_array()).zip(light.to_array()) { // r, g + b, but not alpha
+            *c = f32::min(ambient + (*c as f32)*light, 255.0) as u8;
+        }
+    }
+    color.from_vec(color_vec).unwrap();
+}
+
+// 0 -> 1 smoothly, over [0, 1]
+fn smoothstep(x: f32) -> f32 {
+    let x = x.clamp(0.0, 1.0);
+    x * x * (3.0 - 2.0 * x)
+}
@@ -42,11 +42,11 @@ where T: ColorSpace + Copy {
     Ok(())
 }
 
-pub fn line_3d<T>(image: &mut Image<T>, start: Vec3, end: Vec3, transform: Affine3A, color: T) -> Result<(), String>
+pub fn line_3d<T>(image: &mut Image<T>, start: Vec3, end: Vec3, transform: Mat4, color: T) -> Result<(), String>
 where T: ColorSpace + Copy 
 {
-    let mut start = transform.transform_point3(start);
-    let mut end = transform.transform_point3(end);
+    let mut start = transform.project_point3(start);
+    let mut end = transform.project_point3(end);
     //println!("start: {}; end: {}", start, end);
     let mut steep = false;
 
@@ -92,7 +92,7 @@ where T: ColorSpace + Copy
 
 
 /// Adds lines on [-1, 1] for the 3 axes; red for -ve values, blue for +ve.
-pub fn add_axis_lines(mut image: &mut Image<RGB>, transform: Affine3A) {
+pub fn add_axis_lines(mut image: &mut Image<RGB>, transform: Mat4) {
     let mid = Vec3::new(0.0, 0.0, 0.0);
     let x_neg = Vec3::new(-1.0, 0.0, 0.0);
     let x_pos = Vec3::new(1.0, 0.0, 0.0);
@@ -1,18 +1,19 @@
-mod tgaimage;
-mod line;
-mod obj;
-mod rasterizer;
-mod shaders;
-mod transform;
-mod model;
-
-use crate::line::add_axis_lines;
-use crate::shaders::*;
-use crate::tgaimage::*;
-use crate::obj::*;
-use crate::transform::*;
-use crate::rasterizer::triangle;
-use crate::model::*;
+use renderer::line::add_axis_lines;
+use renderer::shaders::*;
+use renderer::tgaimage::*;
+use renderer::obj::*;
+use renderer::transform::*;
+use renderer::rasterizer::{sort_back_to_front, BlendMode, CullMode, DrawStats, RasterState};
+use renderer::msaa::{MsaaTarget, SampleCount};
+use renderer::abuffer::ABuffer;
+use renderer::framebuffer::Framebuffer;
+use renderer::outline::{draw_selection_outline, Outline};
+use renderer::deferred::{GBuffer, GChannel};
+use renderer::ssao::{Ssao, SsaoConfig};
+use renderer::tiled::TiledRasterizer;
+use renderer::model::*;
+use renderer::light::{Attenuation, Light};
+use renderer::shadows::{CascadeConfig, CascadedShadowMap, CubeShadowMap};
 use glam::*;
 use std::{env, time};
 
@@ -22,66 +23,205 @@ fn main() {
 
     let obj_name = "diablo3_pose";
 
+    // output format is picked from the extension (eg `cargo run -- png`)
+    let extension = env::args().nth(1).unwrap_or(String::from("tga"));
+
     // instantiate common things
     let (height, width) = (1024, 1024);
     let eye = Vec3::new(1.0, 1.0, 4.0);
     let centre = Vec3::ZERO;
     let up = Vec3::Y;
-    let light_source = Vec3::new(1.0, 1.0, 0.0);
+    let sun_dir = Vec3::new(1.0, 1.0, 0.0);
+    // the sun casts the shadows (so goes first); the torch just adds some warm light in front of the model
+    let lights = [
+        Light::directional(sun_dir),
+        Light::Point {
+            position: Vec3::new(-0.6, 0.2, 1.0),
+            color: Vec3::new(1.0, 0.6, 0.3),
+            intensity: 0.8,
+            attenuation: Attenuation { constant: 1.0, linear: 0.7, quadratic: 1.8 }
+        }
+    ];
     
-    let obj_faces = parse_obj(&format!("assets/{obj_name}/{obj_name}.obj"));
-    let texture_image: Image<RGB> = convert_from_tinytga(&format!("assets/{obj_name}/{obj_name}_diffuse.tga"));
-    let normal_image: Image<RGB> = convert_from_tinytga(&format!("assets/{obj_name}/{obj_name}_nm.tga"));
-    let tangent_normal_image: Image<RGB> = convert_from_tinytga(&format!("assets/{obj_name}/{obj_name}_nm_tangent.tga"));
-    let specular_image: Image<Grayscale> = convert_from_tinytga(&format!("assets/{obj_name}/{obj_name}_spec.tga"));
-
-    let transform = initialize_transform(height, width, eye, centre, up);
-    let depth_transform = initialize_transform(height, width, light_source, centre, up);
-    let shadow_transform = 
-        depth_transform.get_whole_transform() * (
-            transform 
-            .get_whole_transform() // this transforms from object space into screen space,
-            .inverse() // so its inverse transforms from screen space to object space,
-        ); // so the overall effect is: screen space -> object space -> shadow screen space
-
-    let model = Model {
-        texture_image,
-        normal_image,
-        tangent_normal_image,
-        specular_image
+    // broken statements are skipped (with a warning), rather than failing the whole render
+    let (mesh, warnings) = parse_obj_lenient(&format!("assets/{obj_name}/{obj_name}.obj")).unwrap();
+    for warning in warnings {
+        eprintln!("warning: {warning}");
+    }
+
+    // models with material libraries bring their own maps; otherwise, use the images next to the .obj
+    // (RGBA, so materials' alpha survives until blending)
+    // (baked ambient occlusion is optional, see examples/bake_ao.rs)
+    let model: Model<RGBA> = if mesh.materials.is_empty() {
+        let mut model = Model::new(
+            Image::read_tga_file(&format!("assets/{obj_name}/{obj_name}_diffuse.tga")).unwrap(),
+            Image::read_tga_file(&format!("assets/{obj_name}/{obj_name}_nm.tga")).unwrap(),
+            Image::read_tga_file(&format!("assets/{obj_name}/{obj_name}_nm_tangent.tga")).unwrap(),
+            Image::read_tga_file(&format!("assets/{obj_name}/{obj_name}_spec.tga")).unwrap()
+        );
+        if let Ok(ao_image) = Image::read_tga_file(&format!("assets/{obj_name}/{obj_name}_ao.tga")) {
+            model.ao_image = ao_image;
+        }
+        model
+    } else {
+        Model::from_materials(&mesh.materials)
     };
 
-    // shadow shader (including depth shader)
-    let mut depth_img: Image<RGB> = Image::new(width, height);
-    let mut shadowbuffer = vec![f32::MIN; width * height];
+    // opaque faces get drawn first, as usual; then transparent ones get composited over them
+    // (by index, so the passes below build each face from the mesh as they go)
+    let (transparent_faces, opaque_faces): (Vec<usize>, Vec<usize>) = (0..mesh.faces.len())
+        .partition(|&idx| model.is_transparent(mesh.face_materials[idx]));
+
+    // the sun is directional, so capture the [-1, 1] cube around centre orthographically, from its direction
+    let light_source = centre + sun_dir;
+    let light_distance = (light_source - centre).length();
+    let depth_projection = Projection::Orthographic { 
+        left: -1.0, right: 1.0, bottom: -1.0, top: 1.0, 
+        near: light_distance - 1.0, far: light_distance + 1.0 
+    };
+
+    let transform = initialize_transform(height, width, eye, centre, up, Projection::default());
+    let depth_transform = initialize_transform(height, width, light_source, centre, up, depth_projection);
+    let shadow_transform = depth_transform.get_whole_transform(); // object space -> shadow screen space
+    // back faces are hidden behind front faces anyway, so skip them;
+    // the depth pass keeps only back faces instead, so lit surfaces aren't compared against their own depth (ie no shadow acne)
+    let raster_state = RasterState { cull_mode: CullMode::Back, ..RasterState::default() };
+    let depth_raster_state = RasterState { cull_mode: CullMode::Front, ..RasterState::default() };
+    // transparent faces show what's behind them (back faces included), without hiding it from later transparent faces
+    let transparent_raster_state = RasterState {
+        cull_mode: CullMode::None,
+        blend: Some(BlendMode::Alpha),
+        depth_write: false,
+        ..RasterState::default()
+    };
 
-    let mut shadow_img: Image<RGB> = Image::new(width, height);
-    let mut zbuffer = vec![f32::MIN; width * height];
+    // shadow shader (including depth shader)
+    let mut depth_framebuffer: Framebuffer<RGB> = Framebuffer::new(width, height, 1);
+    let mut shadow_framebuffer: Framebuffer<RGBA> = Framebuffer::new(width, height, 1);
 
     // instantiate shaders
-    let mut depth_shader = DepthShader::new(light_source, depth_transform.clone());
+    let mut depth_shader = DepthShader::new(depth_transform.clone());
 
     // timed block //
     let now = time::Instant::now();
 
     // first, calculate shadowbuffer
-    for obj_face in obj_faces.clone() {
-        let ndc = Shader::<RGB>::vertex(&mut depth_shader, obj_face.clone(), light_source);
-        let depth_screen_coords = ndc.map(|v| depth_transform.viewport_transform(v));
-        triangle(&mut depth_img, &depth_shader, depth_screen_coords,  &mut shadowbuffer);
+    // (triangles get binned into tiles, which are then shaded in parallel)
+    let mut depth_rasterizer = TiledRasterizer::new(width, height, depth_raster_state);
+    for obj_face in &mesh {
+        let (clip_coords, varying) = Shader::<RGB>::vertex(&mut depth_shader, obj_face);
+        depth_rasterizer.draw_triangle(&depth_transform, clip_coords, varying);
     }
+    depth_rasterizer.render(&mut depth_framebuffer, &depth_shader);
+    let depth_img = depth_framebuffer.colors.remove(0);
+    // (the raw shadow buffer too, before the shadow shader takes it)
+    depth_framebuffer.depth.write_pfm_file("output/depth.pfm", true).unwrap();
 
-    // instantiate and use actual shader
-    let mut shadow_shader = ShadowShader::new(model, transform, shadow_transform, shadowbuffer);
-    for obj_face in obj_faces {
-        let ndc = Shader::<RGB>::vertex(&mut shadow_shader, obj_face.clone(), light_source);
-        let screen_coords = ndc.map(|v| transform.viewport_transform(v));
-        triangle(&mut shadow_img, &shadow_shader, screen_coords,  &mut zbuffer);
+    // instantiate and use actual shader (the shadow map's depth buffer goes with it)
+    let mut shadow_shader = ShadowShader::new(model.clone(), transform, shadow_transform, depth_framebuffer.depth, &lights);
+    // the torch casts shadows too, in every direction (in every shader that lights it)
+    let torch_shadow_map = match lights[1] {
+        Light::Point { position, .. } => Some(CubeShadowMap::render(&mesh, position, 512, 0.05, 5.0)),
+        _ => None
+    };
+    if let Some(torch_shadow_map) = &torch_shadow_map {
+        shadow_shader.add_cube_shadow_map(1, torch_shadow_map.clone());
+    }
+    let mut shadow_rasterizer = TiledRasterizer::new(width, height, raster_state);
+    for &idx in &opaque_faces {
+        let (clip_coords, varying) = Shader::<RGBA>::vertex(&mut shadow_shader, mesh.face(idx));
+        shadow_rasterizer.draw_triangle(&transform, clip_coords, varying);
     }
+    shadow_rasterizer.render(&mut shadow_framebuffer, &shadow_shader);
+
+    // (in any order: the A-buffer sorts them per pixel, so intersecting transparent faces blend correctly too)
+    let mut abuffer = ABuffer::new(width, height);
+    let mut transparent_stats = DrawStats::default();
+    for &idx in &transparent_faces {
+        let (clip_coords, varying) = Shader::<RGBA>::vertex(&mut shadow_shader, mesh.face(idx));
+        abuffer.draw_triangle(&shadow_shader, &varying, &transparent_raster_state, &transform, clip_coords, &shadow_framebuffer.depth, &mut transparent_stats);
+    }
+    abuffer.resolve(&mut shadow_framebuffer.colors[0]);
 
     let time_taken = now.elapsed();
     // end of timed block //
+
+    // the same again, anti-aliased (4 samples per pixel, but still shaded once per pixel)
+    let mut msaa_target: MsaaTarget<RGBA> = MsaaTarget::new(width, height, SampleCount::X4);
+    let mut msaa_stats = DrawStats::default();
+    for &idx in &opaque_faces {
+        let (clip_coords, varying) = Shader::<RGBA>::vertex(&mut shadow_shader, mesh.face(idx));
+        msaa_target.draw_triangle(&shadow_shader, &varying, &raster_state, &transform, clip_coords, &mut msaa_stats);
+    }
+    // (the A-buffer isn't multisampled, so this sorts transparent faces back to front instead)
+    for idx in sort_back_to_front(&transform, &mesh, &transparent_faces) {
+        let (clip_coords, varying) = Shader::<RGBA>::vertex(&mut shadow_shader, mesh.face(idx));
+        msaa_target.draw_triangle(&shadow_shader, &varying, &transparent_raster_state, &transform, clip_coords, &mut msaa_stats);
+    }
+    let shadow_msaa_img = msaa_target.resolve();
+
+    // select part of the model (its head, going by height), then outline it over a copy of the render
+    let selected_faces: Vec<usize> = opaque_faces
+        .iter()
+        .copied()
+        .filter(|&idx| mesh.faces[idx].iter().all(|&i| mesh.positions[i as usize].y > 0.45))
+        .collect();
+    let mut outline_framebuffer = shadow_framebuffer.clone();
+    let mut outline_stats = DrawStats::default();
+    draw_selection_outline(&mut outline_framebuffer, &mut shadow_shader, &mesh, &selected_faces, &raster_state, &transform, &Outline::default(), &mut outline_stats);
+
+    // cascaded shadows: the sun's shadow map gets split up along the view frustum, rather than covering one fixed box
+    // (the debug render tints each cascade its own color)
+    let cascade_config = CascadeConfig { max_distance: 6.0, lambda: 0.25, ..CascadeConfig::default() };
+    shadow_shader.set_cascaded_shadow_map(CascadedShadowMap::render(&mesh, &transform, sun_dir, &cascade_config));
+    let mut cascade_images = Vec::new();
+    for debug in [false, true] {
+        shadow_shader.set_debug_cascades(debug);
+        let mut cascade_rasterizer = TiledRasterizer::new(width, height, raster_state);
+        for &idx in &opaque_faces {
+            let (clip_coords, varying) = Shader::<RGBA>::vertex(&mut shadow_shader, mesh.face(idx));
+            cascade_rasterizer.draw_triangle(&transform, clip_coords, varying);
+        }
+        let mut cascade_framebuffer: Framebuffer<RGBA> = Framebuffer::new(width, height, 1);
+        cascade_rasterizer.render(&mut cascade_framebuffer, &shadow_shader);
+        cascade_images.push(cascade_framebuffer.colors.remove(0));
+    }
     
+    // deferred: the geometry pass fills the G-buffer, then every pixel gets lit once
+    let mut gbuffer: GBuffer<RGBA> = GBuffer::new(width, height);
+    let mut gbuffer_shader = GBufferShader::new(model.clone(), transform);
+    let mut gbuffer_stats = DrawStats::default();
+    for &idx in &opaque_faces {
+        let (clip_coords, varying) = GeometryShader::<RGBA>::vertex(&mut gbuffer_shader, mesh.face(idx));
+        gbuffer.draw_triangle(&gbuffer_shader, &varying, &raster_state, &transform, clip_coords, &mut gbuffer_stats);
+    }
+    // ambient occlusion from the G-buffer's depth, which then darkens the ambient light in crevices
+    // (normals get reconstructed from depth: half of the model mirrors the other's uvs, so its object space normal map is wrong there,
+    // but models without that problem can pass `Some(&gbuffer.normals())` instead)
+    let ssao_img = Ssao::new(SsaoConfig::default()).occlusion(&gbuffer.depth, None, &transform);
+    let mut deferred_shader = DeferredLightingShader::new(transform, &lights);
+    deferred_shader.set_occlusion(ssao_img.clone());
+    if let Some(torch_shadow_map) = &torch_shadow_map {
+        deferred_shader.add_cube_shadow_map(1, torch_shadow_map.clone());
+    }
+    let mut deferred_img: Image<RGBA> = Image::new(width, height);
+    gbuffer.shade(&deferred_shader, &mut deferred_img);
+
+    // physically based: the same lights, lit in linear color
+    // (the model has no metallic/roughness maps, so it's all fully rough dielectric)
+    let mut pbr_shader = PbrShader::new(model, transform, &lights);
+    pbr_shader.set_occlusion(ssao_img.clone());
+    if let Some(torch_shadow_map) = torch_shadow_map {
+        pbr_shader.add_cube_shadow_map(1, torch_shadow_map);
+    }
+    let mut pbr_rasterizer = TiledRasterizer::new(width, height, raster_state);
+    for &idx in &opaque_faces {
+        let (clip_coords, varying) = Shader::<RGBA>::vertex(&mut pbr_shader, mesh.face(idx));
+        pbr_rasterizer.draw_triangle(&transform, clip_coords, varying);
+    }
+    let mut pbr_framebuffer: Framebuffer<RGBA> = Framebuffer::new(width, height, 1);
+    pbr_rasterizer.render(&mut pbr_framebuffer, &pbr_shader);
+
     /* 
     add_axis_lines(&mut gouraud_img, transform.get_whole_transform());
     add_axis_lines(&mut normal_map_img, transform.get_whole_transform());
@@ -90,8 +230,30 @@ fn main() {
     */
 
     println!("{:?}", time_taken);
-    depth_img.write_tga_file("output/depth.tga", true, false).unwrap();
-    shadow_img.write_tga_file("output/shadow.tga", true, false).unwrap();
+    for (pass, stats) in [
+        ("depth", depth_rasterizer.stats()),
+        ("shadow", shadow_rasterizer.stats()),
+        ("transparent", transparent_stats)
+    ] {
+        println!("{pass} pass: culled {} of {} triangles", stats.culled, stats.triangles);
+    }
+    if abuffer.merged() > 0 {
+        println!("A-buffer: merged {} fragments, past {} per pixel", abuffer.merged(), abuffer.max_fragments);
+    }
+    depth_img.write_file(&format!("output/depth.{extension}"), true).unwrap();
+    // (the RGBA renders are cleared to a = 0, so they're written as RGB to stay opaque)
+    shadow_framebuffer.colors[0].to_rgb().write_file(&format!("output/shadow.{extension}"), true).unwrap();
+    shadow_framebuffer.depth.write_pfm_file("output/shadow.pfm", true).unwrap();
+    shadow_msaa_img.to_rgb().write_file(&format!("output/shadow_msaa.{extension}"), true).unwrap();
+    cascade_images[0].to_rgb().write_file(&format!("output/shadow_cascades.{extension}"), true).unwrap();
+    cascade_images[1].to_rgb().write_file(&format!("output/shadow_cascades_debug.{extension}"), true).unwrap();
+    outline_framebuffer.colors[0].to_rgb().write_file(&format!("output/outline.{extension}"), true).unwrap();
+    pbr_framebuffer.colors[0].to_rgb().write_file(&format!("output/pbr.{extension}"), true).unwrap();
+    deferred_img.to_rgb().write_file(&format!("output/deferred.{extension}"), true).unwrap();
+    ssao_img.write_file(&format!("output/ssao.{extension}"), true).unwrap();
+    for channel in GChannel::ALL {
+        gbuffer.channel_image(channel).write_file(&format!("output/gbuffer_{}.{extension}", channel.name()), true).unwrap();
+    }
 }
 
 
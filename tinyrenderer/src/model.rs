@@ -1,86 +1,294 @@
+use std::{path::Path, sync::{Arc, OnceLock}};
 use glam::{Vec2, Vec3};
 
-use crate::{ColorSpace, Grayscale, Image, RGB};
+use crate::{mtl::Material, ColorSpace, Grayscale, Image, RGB, RGBA};
 
 /// Represents a model.
 /// A model contains ObjFaces (vertices, texture coordinates, normals).
-/// It also contains texture, normal, tangent normal, and specular images.
+/// It also contains texture, normal, tangent normal, specular and (optionally, eg baked) ambient occlusion images, used by faces without a material,
+/// plus optional metallic, roughness and emissive images for physically based shading (see `PbrShader`),
+/// and the materials (from the .obj's material libraries), whose maps are loaded on demand.
 /// There are also helper functions.
 #[derive(Clone)]
 pub struct Model<T: ColorSpace + Copy> {
     pub texture_image: Image<T>,
     pub normal_image: Image<RGB>,
     pub tangent_normal_image: Image<RGB>,
-    pub specular_image: Image<Grayscale>
+    pub specular_image: Image<Grayscale>,
+    pub ao_image: Image<Grayscale>, // empty if there isn't one
+    pub metallic_image: Image<Grayscale>, // likewise
+    pub roughness_image: Image<Grayscale>,
+    pub emissive_image: Image<RGB>,
+    pub materials: Vec<Arc<MaterialMaps<T>>> // shared, so clones of the model don't load maps again
+}
+
+/// A material, along with its texture maps.
+/// Each map is only read from file the first time it's used; if it's missing (or can't be read), the material's constants are used instead.
+pub struct MaterialMaps<T: ColorSpace + Copy> {
+    pub material: Material,
+    diffuse_map: OnceLock<Option<Image<T>>>,
+    specular_map: OnceLock<Option<Image<Grayscale>>>,
+    tangent_normal_map: OnceLock<Option<Image<RGB>>>,
+    alpha_map: OnceLock<Option<Image<Grayscale>>>,
+    metallic_map: OnceLock<Option<Image<Grayscale>>>,
+    roughness_map: OnceLock<Option<Image<Grayscale>>>,
+    emissive_map: OnceLock<Option<Image<RGB>>>,
+    occlusion_map: OnceLock<Option<Image<Grayscale>>>
 }
 
-// Converting [0, 1] coordinates into the relevant image's pixel coordinates.
 impl <T: ColorSpace + Copy> Model<T> {
-    pub fn texture_pixel_coords(&self, x: f32, y: f32) -> Vec2 {
-        if x>1.0 || x<0.0 || y>1.0 || y<0.0 {
-            panic!("x or y ({x}, {y}) is out of bounds ([0, 1])");
+    pub fn new(texture_image: Image<T>, normal_image: Image<RGB>, tangent_normal_image: Image<RGB>, specular_image: Image<Grayscale>) -> Self {
+        Model {
+            texture_image,
+            normal_image,
+            tangent_normal_image,
+            specular_image,
+            ao_image: Image::new(0, 0),
+            metallic_image: Image::new(0, 0),
+            roughness_image: Image::new(0, 0),
+            emissive_image: Image::new(0, 0),
+            materials: Vec::new()
         }
-        let pixel_x = (x * self.texture_image.width as f32).floor();
-        let pixel_y = (self.texture_image.height as f32 - (y * self.texture_image.height as f32)).floor(); //strangely, need to vflip
-        Vec2::new(pixel_x, pixel_y)
     }
 
-    pub fn normal_pixel_coords(&self, x: f32, y: f32) -> Vec2 {
-        if x>1.0 || x<0.0 || y>1.0 || y<0.0 {
-            panic!("x or y ({x}, {y}) is out of bounds ([0, 1])");
+    // a model whose faces all have materials, so there are no default images
+    // (their maps must be TGAs, see `Material`)
+    pub fn from_materials(materials: &[Material]) -> Self {
+        Model {
+            materials: materials
+                .iter()
+                .map(|material| Arc::new(MaterialMaps::new(material.clone())))
+                .collect(),
+            ..Model::new(Image::new(0, 0), Image::new(0, 0), Image::new(0, 0), Image::new(0, 0))
         }
-        let pixel_x = (x * self.normal_image.width as f32).floor();
-        let pixel_y = (self.texture_image.height as f32 - (y * self.texture_image.height as f32)).floor();
-        Vec2::new(pixel_x, pixel_y)
     }
+}
 
-    pub fn tang_normal_pixel_coords(&self, x: f32, y: f32) -> Vec2 {
-        if x>1.0 || x<0.0 || y>1.0 || y<0.0 {
-            panic!("x or y ({x}, {y}) is out of bounds ([0, 1])");
+impl <T: ColorSpace + Copy> MaterialMaps<T> {
+    pub fn new(material: Material) -> Self {
+        MaterialMaps {
+            material,
+            diffuse_map: OnceLock::new(),
+            specular_map: OnceLock::new(),
+            tangent_normal_map: OnceLock::new(),
+            alpha_map: OnceLock::new(),
+            metallic_map: OnceLock::new(),
+            roughness_map: OnceLock::new(),
+            emissive_map: OnceLock::new(),
+            occlusion_map: OnceLock::new()
         }
-        let pixel_x = (x * self.tangent_normal_image.width as f32).floor();
-        let pixel_y = (self.tangent_normal_image.height as f32 - (y * self.tangent_normal_image.height as f32)).floor();
-        Vec2::new(pixel_x, pixel_y)
     }
 
-    pub fn specular_pixel_coords(&self, x: f32, y: f32) -> Vec2 {
-        if x>1.0 || x<0.0 || y>1.0 || y<0.0 {
-            panic!("x or y ({x}, {y}) is out of bounds ([0, 1])");
+    pub fn diffuse_map(&self) -> Option<&Image<T>> {
+        self.diffuse_map
+            .get_or_init(|| load_map(self.material.diffuse_map.as_deref()))
+            .as_ref()
+    }
+
+    pub fn specular_map(&self) -> Option<&Image<Grayscale>> {
+        self.specular_map
+            .get_or_init(|| load_map(self.material.specular_map.as_deref()))
+            .as_ref()
+    }
+
+    // NOTE: map_Bump is assumed to be a tangent space normal map too, as most exporters write normal maps there
+    pub fn tangent_normal_map(&self) -> Option<&Image<RGB>> {
+        self.tangent_normal_map
+            .get_or_init(|| load_map(self.material.normal_map.as_deref().or(self.material.bump_map.as_deref())))
+            .as_ref()
+    }
+
+    pub fn alpha_map(&self) -> Option<&Image<Grayscale>> {
+        self.alpha_map
+            .get_or_init(|| load_map(self.material.alpha_map.as_deref()))
+            .as_ref()
+    }
+
+    pub fn metallic_map(&self) -> Option<&Image<Grayscale>> {
+        self.metallic_map
+            .get_or_init(|| load_map(self.material.metallic_map.as_deref()))
+            .as_ref()
+    }
+
+    pub fn roughness_map(&self) -> Option<&Image<Grayscale>> {
+        self.roughness_map
+            .get_or_init(|| load_map(self.material.roughness_map.as_deref()))
+            .as_ref()
+    }
+
+    pub fn emissive_map(&self) -> Option<&Image<RGB>> {
+        self.emissive_map
+            .get_or_init(|| load_map(self.material.emissive_map.as_deref()))
+            .as_ref()
+    }
+
+    pub fn occlusion_map(&self) -> Option<&Image<Grayscale>> {
+        self.occlusion_map
+            .get_or_init(|| load_map(self.material.occlusion_map.as_deref()))
+            .as_ref()
+    }
+}
+
+// only TGAs can be read, so anything else (eg a png) is reported + treated as missing
+fn load_map<C: ColorSpace + Copy>(path: Option<&Path>) -> Option<Image<C>> {
+    let path = path?;
+    match Image::read_tga_file(&path.to_string_lossy()) {
+        Ok(image) => Some(image),
+        Err(err) => {
+            eprintln!("Couldn't load texture map {}: {err}", path.display());
+            None
         }
-        let pixel_x = (x * self.specular_image.width as f32).floor();
-        let pixel_y = (self.texture_image.height as f32 - (y * self.texture_image.height as f32)).floor();
-        Vec2::new(pixel_x, pixel_y)
     }
 }
 
-// Obtaining useful information from model images.
+// Converting uv coordinates into the relevant image's pixel coordinates.
+// uvs outside of [0, 1] wrap around (ie the texture repeats)
+fn pixel_coords<C: ColorSpace + Copy>(image: &Image<C>, uv: Vec2) -> (usize, usize) {
+    let (width, height) = (image.width as f32, image.height as f32);
+    let uv = uv - uv.floor();
+    let pixel_x = (uv.x * width).floor().min(width - 1.0);
+    let pixel_y = (height - uv.y * height).floor().min(height - 1.0); //strangely, need to vflip
+    (pixel_x as usize, pixel_y as usize)
+}
+
+fn sample<C: ColorSpace + Copy>(image: &Image<C>, uv: Vec2) -> Option<C> {
+    if image.data.is_empty() {
+        return None;
+    }
+    let (x, y) = pixel_coords(image, uv);
+    image.get(x, y).ok()
+}
+
+// normal maps store each component in [0, 255], so bring them back to [-1, 1]
+fn decode_normal(normal_color: RGB) -> Vec3 {
+    Vec3::new(
+        2.0 * (normal_color.r as f32 / 255.0) - 1.0,
+        2.0 * (normal_color.g as f32 / 255.0) - 1.0,
+        2.0 * (normal_color.b as f32 / 255.0) - 1.0,
+    )
+}
+
+// Obtaining useful information from model images, at the given uv coordinates.
+// `material` is the face's material (see `ObjFace`); without one, the default images are used.
 impl <T: ColorSpace + Copy> Model<T> {
-    pub fn get_texture_color(&self, x: usize, y: usize) -> T {
-        self.texture_image.get(x, y).unwrap()
+    fn material(&self, material: Option<usize>) -> Option<&MaterialMaps<T>> {
+        material.and_then(|idx| self.materials.get(idx)).map(Arc::as_ref)
+    }
+
+    pub fn get_texture_color(&self, material: Option<usize>, uv: Vec2) -> T {
+        match self.material(material) {
+            Some(maps) => match maps.diffuse_map() {
+                Some(diffuse_map) => sample(diffuse_map, uv),
+                None => {
+                    let kd = (maps.material.diffuse.clamp(Vec3::ZERO, Vec3::ONE) * 255.0).to_array().map(|c| c as u8);
+                    Some(T::from_rgba(RGBA { r: kd[0], g: kd[1], b: kd[2], a: 255 }))
+                }
+            },
+            None => sample(&self.texture_image, uv)
+        }
+        .unwrap_or(T::white())
+    }
+
+    // object space normal, if there's a normal map for it (materials only have tangent space ones)
+    pub fn get_normal(&self, material: Option<usize>, uv: Vec2) -> Option<Vec3> {
+        match self.material(material) {
+            Some(_) => None,
+            None => sample(&self.normal_image, uv).map(decode_normal)
+        }
+    }
+
+    pub fn get_tangent_normal(&self, material: Option<usize>, uv: Vec2) -> Option<Vec3> {
+        match self.material(material) {
+            Some(maps) => maps.tangent_normal_map().and_then(|map| sample(map, uv)),
+            None => sample(&self.tangent_normal_image, uv)
+        }
+        .map(decode_normal)
+    }
+
+    // what the ambient light gets scaled by, per channel: Ka, or white without a material
+    pub fn get_ambient(&self, material: Option<usize>) -> Vec3 {
+        self.material(material).map_or(Vec3::ONE, |maps| maps.material.ambient)
+    }
+
+    // how unoccluded the ambient light is, in [0, 1] (unoccluded without a map)
+    pub fn get_occlusion(&self, material: Option<usize>, uv: Vec2) -> f32 {
+        match self.material(material) {
+            Some(maps) => maps.occlusion_map().and_then(|map| sample(map, uv)),
+            None => sample(&self.ao_image, uv)
+        }
+        .map_or(1.0, |ao| ao.i as f32 / 255.0)
+    }
+
+    // ie the specular exponent
+    pub fn get_specularity(&self, material: Option<usize>, uv: Vec2) -> f32 {
+        match self.material(material) {
+            Some(maps) => {
+                let scale = maps.specular_map()
+                    .and_then(|map| sample(map, uv))
+                    .map_or(1.0, |specular| specular.i as f32 / 255.0);
+                maps.material.shininess * scale
+            },
+            None => sample(&self.specular_image, uv).map_or(0.0, |specular| specular.i as f32)
+        }
+    }
+
+    // how much specular highlights count: Ks (averaged), or 0.6 without a material
+    // (materials whose illumination model has no highlights get none)
+    pub fn get_specular_weight(&self, material: Option<usize>) -> f32 {
+        match self.material(material) {
+            Some(maps) if maps.material.illum < 2 => 0.0,
+            Some(maps) => maps.material.specular.dot(Vec3::ONE) / 3.0,
+            None => 0.6
+        }
+    }
+
+    // metalness, in [0, 1] (non-metal without a map)
+    pub fn get_metallic(&self, material: Option<usize>, uv: Vec2) -> f32 {
+        match self.material(material) {
+            Some(maps) => maps.material.metallic * maps.metallic_map()
+                .and_then(|map| sample(map, uv))
+                .map_or(1.0, |metallic| metallic.i as f32 / 255.0),
+            None => sample(&self.metallic_image, uv).map_or(0.0, |metallic| metallic.i as f32 / 255.0)
+        }
     }
 
-    pub fn get_normal(&self, x: usize, y: usize) -> Vec3 {
-        let normal_color = self.normal_image.get(x, y).unwrap();
-        Vec3::new(
-            2.0 * (normal_color.r as f32 / 255.0) - 1.0,
-            2.0 * (normal_color.g as f32 / 255.0) - 1.0,
-            2.0 * (normal_color.b as f32 / 255.0) - 1.0,
-        )
+    // perceptual roughness, in [0, 1] (fully rough without a map)
+    pub fn get_roughness(&self, material: Option<usize>, uv: Vec2) -> f32 {
+        match self.material(material) {
+            Some(maps) => maps.material.roughness * maps.roughness_map()
+                .and_then(|map| sample(map, uv))
+                .map_or(1.0, |roughness| roughness.i as f32 / 255.0),
+            None => sample(&self.roughness_image, uv).map_or(1.0, |roughness| roughness.i as f32 / 255.0)
+        }
     }
 
-    pub fn get_tangent_normal(&self, x: usize, y: usize) -> Vec3 {
-        let normal_color = self.tangent_normal_image.get(x, y).unwrap();
-        Vec3::new(
-            2.0 * (normal_color.r as f32 / 255.0) - 1.0,
-            2.0 * (normal_color.g as f32 / 255.0) - 1.0,
-            2.0 * (normal_color.b as f32 / 255.0) - 1.0,
-        )
+    // emitted light, in [0, 1] per channel, still encoded like the texture (nothing without a map)
+    pub fn get_emissive(&self, material: Option<usize>, uv: Vec2) -> Vec3 {
+        let to_vec = |color: RGB| Vec3::new(color.r as f32, color.g as f32, color.b as f32) / 255.0;
+        match self.material(material) {
+            Some(maps) => maps.material.emissive * maps.emissive_map()
+                .and_then(|map| sample(map, uv))
+                .map_or(Vec3::ONE, to_vec),
+            None => sample(&self.emissive_image, uv).map_or(Vec3::ZERO, to_vec)
+        }
     }
 
-    pub fn get_specularity(&self, x: usize, y: usize) -> f32 {
-        self.specular_image
-            .get(x, y)
-            .unwrap()
-            .i as f32
+    // whether faces with this material need blending (and sorting), ie it has a `d` below 1 or a `map_d`
+    pub fn is_transparent(&self, material: Option<usize>) -> bool {
+        self.material(material)
+            .is_some_and(|maps| maps.material.dissolve < 1.0 || maps.material.alpha_map.is_some())
     }
-}
\ No newline at end of file
+
+    // opacity, in [0, 1]
+    pub fn get_alpha(&self, material: Option<usize>, uv: Vec2) -> f32 {
+        match self.material(material) {
+            Some(maps) => {
+                let alpha = maps.alpha_map()
+                    .and_then(|map| sample(map, uv))
+                    .map_or(1.0, |alpha| alpha.i as f32 / 255.0);
+                maps.material.dissolve * alpha
+            },
+            None => 1.0
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use crate::tgaimage::*;
+use crate::shaders::Shader;
+use crate::rasterizer::{bounding_box, rasterize_multisample, screen_triangles, DrawStats, RasterState};
+use crate::transform::Transform;
+use crate::framebuffer::DepthBuffer;
+use std::fmt::Debug;
+use glam::*;
+
+/// How many samples per pixel a multisampled render target keeps.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SampleCount {
+    X2,
+    X4,
+    X8
+}
+
+// The standard (ie Direct3D / Vulkan) sample positions, in 1/16ths of a pixel from its centre, with y pointing down.
+const PATTERN_2X: [(i8, i8); 2] = [(4, 4), (-4, -4)];
+const PATTERN_4X: [(i8, i8); 4] = [(-2, -6), (6, -2), (-6, 2), (2, 6)];
+const PATTERN_8X: [(i8, i8); 8] = [(1, -3), (-1, 3), (5, 1), (-3, -5), (-5, 5), (-7, -1), (3, 7), (7, -7)];
+
+impl SampleCount {
+    pub fn samples(self) -> usize {
+        match self {
+            SampleCount::X2 => 2,
+            SampleCount::X4 => 4,
+            SampleCount::X8 => 8
+        }
+    }
+
+    /// The sample positions, in pixels from the pixel's corner (as `rasterize_multisample` takes them).
+    pub fn pattern(self) -> Vec<Vec2> {
+        let pattern: &[(i8, i8)] = match self {
+            SampleCount::X2 => &PATTERN_2X,
+            SampleCount::X4 => &PATTERN_4X,
+            SampleCount::X8 => &PATTERN_8X
+        };
+        // our rows go up, so flip y
+        pattern
+            .iter()
+            .map(|&(x, y)| Vec2::new(0.5 + x as f32 / 16.0, 0.5 - y as f32 / 16.0))
+            .collect()
+    }
+}
+
+/// A multisampled render target: every pixel has a color + depth per sample.
+///
+/// Coverage and depth testing happen per sample, but the fragment shader only runs once per pixel,
+/// and its color is written to every sample that passed. `resolve` then averages the samples into an image,
+/// so edges get anti-aliased without shading any more fragments than usual.
+pub struct MsaaTarget<T> {
+    pub width: usize,
+    pub height: usize,
+    pub sample_count: SampleCount,
+    pattern: Vec<Vec2>,
+    colors: Vec<T>, // sample i of pixel (x, y) is at (x + y*width)*samples + i
+    pub depth: DepthBuffer // one depth per sample, laid out like `colors` (ie it's (width * samples) x height); its `compare` tests each sample
+}
+
+impl<T: ColorSpace + Copy + Debug> MsaaTarget<T> {
+    /// Makes a `width` x `height` target, cleared to `T::new()` and the depth buffer's clear value.
+    pub fn new(width: usize, height: usize, sample_count: SampleCount) -> Self {
+        let samples = width * height * sample_count.samples();
+        MsaaTarget {
+            width,
+            height,
+            sample_count,
+            pattern: sample_count.pattern(),
+            colors: vec![T::new(); samples],
+            depth: DepthBuffer::new(width * sample_count.samples(), height)
+        }
+    }
+
+    /// Like `rasterizer::draw_triangle`: clips (and culls) a triangle, then rasterizes whatever is left.
+    pub fn draw_triangle<S: Shader<T>>(
+        &mut self,
+        shader: &S,
+        varying: &S::Varying,
+        state: &RasterState,
+        transform: &Transform,
+        clip_coords: [Vec4; 3],
+        stats: &mut DrawStats
+    ) {
+        for (screen_coords, bary_coords) in screen_triangles(state, transform, clip_coords, stats) {
+            self.triangle(shader, varying, state, screen_coords, bary_coords);
+        }
+    }
+
+    /// Like `rasterizer::triangle`, but depth tests each covered sample, and shades each pixel (at most) once.
+    pub fn triangle<S: Shader<T>>(
+        &mut self,
+        shader: &S,
+        varying: &S::Varying,
+        state: &RasterState,
+        screen_coords: [Vec4; 3],
+        bary_coords: [Vec3; 3]
+    ) {
+        let (bboxmin, bboxmax) = bounding_box(&screen_coords, self.width, self.height);
+        let (width, samples) = (self.width, self.pattern.len());
+        let (colors, depth) = (&mut self.colors, &mut self.depth);
+
+        rasterize_multisample(state, &screen_coords, &bary_coords, bboxmin, bboxmax, &self.pattern, |p_x, p_y, coverage, sample_z, bc_clip| {
+            let first = (p_x + p_y*width) * samples;
+
+            // which covered samples pass the depth test
+            let passed = (0..samples)
+                .filter(|&i| coverage & (1 << i) != 0 && depth.test(first + i, sample_z[i]))
+                .fold(0u32, |mask, i| mask | (1 << i));
+            if passed == 0 {
+                return;
+            }
+
+            let mut color = T::white();
+            if shader.fragment(varying, bc_clip, &mut color) {
+                return;
+            }
+            for i in (0..samples).filter(|&i| passed & (1 << i) != 0) {
+                state.merge(color, sample_z[i], &mut colors[first + i], &mut depth.data[first + i]);
+            }
+        });
+    }
+
+    /// Averages each pixel's samples into an image.
+    pub fn resolve(&self) -> Image<T> {
+        let samples = self.pattern.len();
+        let mut image: Image<T> = Image::new(self.width, self.height);
+
+        for (pixel, colors) in image.data.iter_mut().zip(self.colors.chunks_exact(samples)) {
+            let mut sums = vec![0u32; T::BPP as usize];
+            for color in colors {
+                for (sum, channel) in sums.iter_mut().zip(color.to_vec()) {
+                    *sum += channel as u32;
+                }
+            }
+            let channels = sums.iter().map(|&sum| ((sum as f32 / samples as f32).round()) as u8).collect();
+            pixel.from_vec(channels).unwrap();
+        }
+        image
+    }
+
+    /// Resets every sample to `T::new()` and the depth buffer's clear value.
+    pub fn clear(&mut self) {
+        self.colors.fill(T::new());
+        self.depth.clear();
+    }
+}
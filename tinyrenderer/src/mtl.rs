@@ -0,0 +1,168 @@
+use std::{fs, path::{Path, PathBuf}};
+use glam::*;
+use crate::obj::{error_column, ObjError, ObjErrorKind};
+use nom::{
+    character::complete::{space0, space1, u32},
+    number::complete::float,
+    sequence::tuple,
+    IResult,
+};
+
+/// A material from a .mtl file.
+/// Texture maps are stored as paths (relative to the working directory), and are only loaded when used (see `Model`).
+/// Only TGA maps can be loaded; other formats (eg png or jpg) get reported and ignored, falling back to the constants.
+#[derive(Clone, Debug)]
+pub struct Material {
+    pub name: String,
+    pub ambient: Vec3, // Ka; scales the shaders' ambient light (white if unset, so it's left as is)
+    pub diffuse: Vec3, // Kd
+    pub specular: Vec3, // Ks
+    pub shininess: f32, // Ns
+    pub dissolve: f32, // d (or 1 - Tr); ie opacity
+    pub illum: u32, // 0 + 1 have no specular highlights
+    pub diffuse_map: Option<PathBuf>, // map_Kd
+    pub specular_map: Option<PathBuf>, // map_Ks
+    pub bump_map: Option<PathBuf>, // map_Bump/bump
+    pub normal_map: Option<PathBuf>, // norm
+    pub alpha_map: Option<PathBuf>, // map_d
+    // the PBR extension (as exported for glTF style, metallic-roughness materials)
+    pub roughness: f32, // Pr
+    pub metallic: f32, // Pm
+    pub emissive: Vec3, // Ke
+    pub roughness_map: Option<PathBuf>, // map_Pr
+    pub metallic_map: Option<PathBuf>, // map_Pm
+    pub emissive_map: Option<PathBuf>, // map_Ke
+    pub occlusion_map: Option<PathBuf>, // map_ao (ambient occlusion, eg baked)
+}
+
+impl Material {
+    pub fn new(name: &str) -> Self {
+        Material {
+            name: name.to_string(),
+            ambient: Vec3::ONE,
+            diffuse: Vec3::ONE,
+            specular: Vec3::ZERO,
+            shininess: 0.0,
+            dissolve: 1.0,
+            illum: 2,
+            diffuse_map: None,
+            specular_map: None,
+            bump_map: None,
+            normal_map: None,
+            alpha_map: None,
+            roughness: 1.0,
+            metallic: 0.0,
+            emissive: Vec3::ZERO,
+            roughness_map: None,
+            metallic_map: None,
+            emissive_map: None,
+            occlusion_map: None
+        }
+    }
+}
+
+// parse a material library from file
+// unknown statements are skipped; malformed ones are errors, unless there are `warnings` to collect them in (see `ObjError::report`)
+pub fn parse_mtl(filepath: &Path, mut warnings: Option<&mut Vec<ObjError>>) -> Result<Vec<Material>, ObjError> {
+    let contents = fs::read_to_string(filepath)
+        .map_err(|error| ObjError::Io { path: filepath.to_path_buf(), error })?;
+    let directory = filepath.parent().unwrap_or(Path::new(""));
+
+    let mut materials: Vec<Material> = Vec::new();
+
+    for (line_idx, full_line) in contents.lines().enumerate() {
+        let line = full_line.trim_start();
+        let (keyword, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+
+        if keyword == "newmtl" {
+            materials.push(Material::new(rest));
+            continue;
+        }
+
+        // everything else applies to the latest material
+        let Some(material) = materials.last_mut() else { continue };
+        let parsed = match keyword {
+            "Ka" => parse_color(rest).map(|(_, color)| material.ambient = color),
+            "Kd" => parse_color(rest).map(|(_, color)| material.diffuse = color),
+            "Ks" => parse_color(rest).map(|(_, color)| material.specular = color),
+            "Ns" => float(rest).map(|(_, value)| material.shininess = value),
+            "d" => float(rest).map(|(_, value)| material.dissolve = value),
+            "Tr" => float(rest).map(|(_, value)| material.dissolve = 1.0 - value),
+            "illum" => u32(rest).map(|(_, value)| material.illum = value),
+            "map_Kd" => parse_map(rest, directory).map(|(_, map)| material.diffuse_map = Some(map)),
+            "map_Ks" => parse_map(rest, directory).map(|(_, map)| material.specular_map = Some(map)),
+            "map_Bump" | "map_bump" | "bump" => parse_map(rest, directory).map(|(_, map)| material.bump_map = Some(map)),
+            "norm" => parse_map(rest, directory).map(|(_, map)| material.normal_map = Some(map)),
+            "map_d" => parse_map(rest, directory).map(|(_, map)| material.alpha_map = Some(map)),
+            "Pr" => float(rest).map(|(_, value)| material.roughness = value),
+            "Pm" => float(rest).map(|(_, value)| material.metallic = value),
+            "Ke" => parse_color(rest).map(|(_, color)| material.emissive = color),
+            "map_Pr" => parse_map(rest, directory).map(|(_, map)| material.roughness_map = Some(map)),
+            "map_Pm" => parse_map(rest, directory).map(|(_, map)| material.metallic_map = Some(map)),
+            "map_Ke" => parse_map(rest, directory).map(|(_, map)| material.emissive_map = Some(map)),
+            "map_ao" | "map_AO" => parse_map(rest, directory).map(|(_, map)| material.occlusion_map = Some(map)),
+            _ => continue
+        };
+
+        if let Err(err) = parsed {
+            ObjError::Parse {
+                path: filepath.to_path_buf(),
+                line: line_idx + 1,
+                column: error_column(full_line, err),
+                text: full_line.to_string(),
+                kind: ObjErrorKind::Malformed("material statement")
+            }.report(&mut warnings)?;
+        }
+    }
+    Ok(materials)
+}
+
+// color parsing (only "r g b"; spectral + CIEXYZ colors aren't supported)
+fn parse_color(input: &str) -> IResult<&str, Vec3> {
+    let (input, (x, _, y, _, z, _)) = tuple((float, space1, float, space1, float, space0))(input)?;
+    Ok((input, Vec3 { x, y, z }))
+}
+
+// texture map parsing
+// options (eg "-bm 0.5", "-clamp on" or "-type sphere") come before the filename, so skip past them
+fn parse_map<'a>(input: &'a str, directory: &Path) -> IResult<&'a str, PathBuf> {
+    let mut tokens = input.split_whitespace().peekable();
+    while let Some(&token) = tokens.peek() {
+        if !token.starts_with('-') {
+            break;
+        }
+        tokens.next();
+        match map_option_args(token) {
+            Some(args) => {
+                for _ in 0..args {
+                    tokens.next();
+                }
+            },
+            // -o, -s + -t take 1 to 3 numbers (and unknown options get the same treatment)
+            None => for _ in 0..3 {
+                match tokens.peek() {
+                    Some(&arg) if arg.parse::<f32>().is_ok() => { tokens.next(); },
+                    _ => break
+                }
+            }
+        }
+    }
+
+    let filename = tokens.collect::<Vec<_>>().join(" "); // filenames can have spaces
+    if filename.is_empty() {
+        return Err(nom::Err::Error(nom::error::Error::new(&input[input.len()..], nom::error::ErrorKind::Eof)));
+    }
+    Ok(("", directory.join(filename)))
+}
+
+// how many arguments a map option takes, if it's always the same
+fn map_option_args(option: &str) -> Option<usize> {
+    match option {
+        "-blendu" | "-blendv" | "-cc" | "-clamp" => Some(1), // on/off
+        "-boost" | "-texres" | "-bm" => Some(1), // a number
+        "-imfchan" | "-type" => Some(1), // eg r, g, b, m, l or z; sphere, cube_top etc
+        "-mm" => Some(2), // base + gain
+        _ => None
+    }
+}
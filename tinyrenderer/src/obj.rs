@@ -1,89 +1,444 @@
-use std::fs;
+use std::{collections::HashMap, error::Error, fmt::{self, Display}, fs, io, ops::Range, path::{Path, PathBuf}};
 use glam::*;
+use crate::mtl::{parse_mtl, Material};
 use nom::{
     bytes::complete::tag,
-    character::complete::{char, multispace0, space1, digit1},
+    character::complete::{char, i64, multispace0, space0, space1},
     number::complete::float,
-    combinator::map_res,
-    sequence::tuple,
-    multi::separated_list0,
+    combinator::{consumed, opt},
+    sequence::{preceded, tuple},
+    multi::separated_list1,
     IResult,
 };
 
 // each .obj face has 3 sets of 3 vertices; actual vertices, textures, and normals
+// it can also have a material (an index into the mesh's materials)
 #[derive(Clone)]
 pub struct ObjFace {
     pub vertices: [Vec3; 3],
     pub texture_vertices: [Vec3; 3],
-    pub normals: [Vec3; 3]
+    pub normals: [Vec3; 3],
+    pub material: Option<usize>
 }
 
-// parse the object from file
-// returns tuple of 3 Vec3; the face vertices, texture vertices, and normal vectors
-pub fn parse_obj(filepath: &str) -> Vec<ObjFace> { 
-    let contents = fs::read_to_string(filepath)
-        .expect(&format!("No such file at this filepath: {filepath}")[..]);
+/// Everything parsed from an .obj file, as an indexed triangle mesh.
+/// Each vertex is a distinct v/vt/vn combination, stored once in the vertex arrays (`positions`, `texture_vertices` and `normals`),
+/// and each face is a triple of indices into them. Iterating the mesh gives each face as an ObjFace.
+/// Materials come from its material libraries (`mtllib`); objects and groups (`o`/`g`) are named runs of faces.
+#[derive(Clone, Default)]
+pub struct Mesh {
+    pub positions: Vec<Vec3>,
+    pub texture_vertices: Vec<Vec3>,
+    pub normals: Vec<Vec3>,
+    pub faces: Vec<[u32; 3]>,
+    pub face_materials: Vec<Option<usize>>,
+    pub materials: Vec<Material>,
+    pub objects: Vec<ObjGroup>,
+    pub groups: Vec<ObjGroup>
+}
+
+impl Mesh {
+    // an empty mesh
+    pub fn new() -> Self {
+        Mesh::default()
+    }
+
+    pub fn face(&self, idx: usize) -> ObjFace {
+        let indices = self.faces[idx].map(|i| i as usize);
+        ObjFace {
+            vertices: indices.map(|i| self.positions[i]),
+            texture_vertices: indices.map(|i| self.texture_vertices[i]),
+            normals: indices.map(|i| self.normals[i]),
+            material: self.face_materials[idx]
+        }
+    }
+
+    pub fn faces(&self) -> Faces<'_> {
+        Faces { mesh: self, idx: 0..self.faces.len() }
+    }
+
+    // adds a vertex, unless there's already an identical one
+    fn add_vertex(&mut self, vertex_ids: &mut HashMap<VertexKey, u32>, key: VertexKey, position: Vec3, texture_vertex: Vec3, normal: Vec3) -> u32 {
+        *vertex_ids.entry(key).or_insert_with(|| {
+            self.positions.push(position);
+            self.texture_vertices.push(texture_vertex);
+            self.normals.push(normal);
+            (self.positions.len() - 1) as u32
+        })
+    }
+}
+
+/// Iterates over a mesh's faces (see `Mesh::faces`).
+pub struct Faces<'a> {
+    mesh: &'a Mesh,
+    idx: Range<usize>
+}
+
+impl Iterator for Faces<'_> {
+    type Item = ObjFace;
+
+    fn next(&mut self) -> Option<ObjFace> {
+        self.idx.next().map(|idx| self.mesh.face(idx))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.idx.size_hint()
+    }
+}
+
+impl ExactSizeIterator for Faces<'_> {}
+
+impl<'a> IntoIterator for &'a Mesh {
+    type Item = ObjFace;
+    type IntoIter = Faces<'a>;
+
+    fn into_iter(self) -> Faces<'a> {
+        self.faces()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ObjGroup {
+    pub name: String,
+    pub faces: Range<usize>
+}
+
+// used for face vertices without a texture vertex
+const DEFAULT_TEXTURE_VERTEX: Vec3 = Vec3::ZERO;
+
+// a face's vertex, ie "v", "v/vt", "v//vn" or "v/vt/vn"
+// indices start from 1; negative indices are relative to the end of the list so far (ie -1 is the latest one)
+type FaceVertex = (i64, Option<i64>, Option<i64>);
+
+// identifies a mesh vertex: the (resolved) v and vt indices, and the normal's bits.
+// normals are compared by value, since faces without vn get their own face normal.
+type VertexKey = (usize, Option<usize>, [u32; 3]);
+
+///// Errors
+
+/// An error from parsing an .obj file (or one of its material libraries).
+#[derive(Debug)]
+pub enum ObjError {
+    Io { path: PathBuf, error: io::Error },
+    Parse { path: PathBuf, line: usize, column: usize, text: String, kind: ObjErrorKind }
+}
+
+/// What was wrong with a statement.
+#[derive(Debug)]
+pub enum ObjErrorKind {
+    Malformed(&'static str), // the kind of statement, eg "vertex"
+    IndexOutOfRange { index: i64, len: usize },
+    UnknownMaterial(String)
+}
+
+impl Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ObjError::Io { path, error } => write!(f, "{}: {error}", path.display()),
+            ObjError::Parse { path, line, column, text, kind } =>
+                write!(f, "{}:{line}:{column}: {kind}\n    {text}", path.display())
+        }
+    }
+}
+
+impl Display for ObjErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ObjErrorKind::Malformed(statement) => write!(f, "malformed {statement}"),
+            ObjErrorKind::IndexOutOfRange { index, len } => write!(f, "index {index} is out of range (there are {len} so far)"),
+            ObjErrorKind::UnknownMaterial(name) => write!(f, "unknown material {name:?}")
+        }
+    }
+}
+
+impl Error for ObjError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ObjError::Io { error, .. } => Some(error),
+            ObjError::Parse { .. } => None
+        }
+    }
+}
+
+impl ObjError {
+    /// In strict mode (ie no `warnings`), returns the error; in lenient mode, collects it so parsing can carry on.
+    pub fn report(self, warnings: &mut Option<&mut Vec<ObjError>>) -> Result<(), ObjError> {
+        match warnings {
+            Some(warnings) => {
+                warnings.push(self);
+                Ok(())
+            },
+            None => Err(self)
+        }
+    }
+}
+
+// the column (starting from 1) where a nom parser failed, given the line it was parsing
+pub fn error_column(line: &str, err: nom::Err<nom::error::Error<&str>>) -> usize {
+    match err {
+        nom::Err::Error(err) | nom::Err::Failure(err) => line.len() - err.input.len() + 1,
+        nom::Err::Incomplete(_) => line.len() + 1
+    }
+}
+
+///// Parsing
+
+/// Parse the object from file.
+/// Faces with more than 3 vertices are triangulated, so each ObjFace is a triangle.
+/// Fails on the first malformed statement (unknown statements are ignored though).
+pub fn parse_obj(filepath: &str) -> Result<Mesh, ObjError> {
+    parse_obj_with(filepath, None)
+}
+
+/// Like `parse_obj`, but malformed statements are skipped and returned as warnings, rather than failing.
+/// Only failing to read the .obj file itself is an error.
+pub fn parse_obj_lenient(filepath: &str) -> Result<(Mesh, Vec<ObjError>), ObjError> {
+    let mut warnings = Vec::new();
+    let mesh = parse_obj_with(filepath, Some(&mut warnings))?;
+    Ok((mesh, warnings))
+}
+
+fn parse_obj_with(filepath: &str, mut warnings: Option<&mut Vec<ObjError>>) -> Result<Mesh, ObjError> {
+    let path = Path::new(filepath);
+    let contents = fs::read_to_string(path)
+        .map_err(|error| ObjError::Io { path: path.to_path_buf(), error })?;
+    let directory = path.parent().unwrap_or(Path::new(""));
 
     let mut vertex_coords = Vec::new();
     let mut normal_coords = Vec::new();
     let mut texture_coords = Vec::new();
-    let mut obj_faces = Vec::new();
+    let mut mesh = Mesh::new();
+    let mut vertex_ids = HashMap::new();
+    let mut current_material = None;
+
+    for (line_idx, line) in contents.lines().enumerate() {
+        let error_at = |column: usize, kind: ObjErrorKind| ObjError::Parse {
+            path: path.to_path_buf(),
+            line: line_idx + 1,
+            column,
+            text: line.to_string(),
+            kind
+        };
 
-    for line in contents.lines() {
         if line.starts_with("v ") {
-            match parse_vertex(&line) {
+            match parse_vertex(line) {
                 Ok((_, coord)) =>vertex_coords.push(coord),
-                Err(_) => continue
+                Err(err) => error_at(error_column(line, err), ObjErrorKind::Malformed("vertex")).report(&mut warnings)?
             }
         }
 
         else if line.starts_with("vt ") {
-            match parse_texture(&line) {
+            match parse_texture(line) {
                 Ok((_, coord)) => texture_coords.push(coord),
-                Err(_) => continue
+                Err(err) => error_at(error_column(line, err), ObjErrorKind::Malformed("texture vertex")).report(&mut warnings)?
             }
         }
 
         else if line.starts_with("vn ") {
-            match parse_normal(&line) {
+            match parse_normal(line) {
                 Ok((_, coord)) => normal_coords.push(coord),
-                Err(_) => continue
+                Err(err) => error_at(error_column(line, err), ObjErrorKind::Malformed("normal")).report(&mut warnings)?
             }
         }
 
         else if line.starts_with("f ") {
-            match parse_face(&line, &vertex_coords, &texture_coords, &normal_coords) {
-                Ok((_, obj_face)) => obj_faces.push(obj_face),
-                Err(_) => continue
+            let face_vertices = match parse_face(line) {
+                Ok((_, face_vertices)) => face_vertices,
+                Err(err) => {
+                    error_at(error_column(line, err), ObjErrorKind::Malformed("face")).report(&mut warnings)?;
+                    continue;
+                }
+            };
+            let vertices: Vec<FaceVertex> = face_vertices.iter().map(|&(_, vertex)| vertex).collect();
+            match add_faces(&mut mesh, &mut vertex_ids, &vertices, &vertex_coords, &texture_coords, &normal_coords) {
+                Ok(n_faces) => mesh.face_materials.extend(std::iter::repeat_n(current_material, n_faces)),
+                Err((vertex_idx, kind)) => {
+                    let column = face_vertices[vertex_idx].0.as_ptr() as usize - line.as_ptr() as usize + 1;
+                    error_at(column, kind).report(&mut warnings)?
+                }
+            }
+        }
+
+        else if let Some(filenames) = line.strip_prefix("mtllib ") {
+            for filename in filenames.split_whitespace() {
+                match parse_mtl(&directory.join(filename), warnings.as_deref_mut()) {
+                    Ok(library) => mesh.materials.extend(library),
+                    Err(err) => err.report(&mut warnings)?
+                }
             }
         }
+
+        else if let Some(name) = line.strip_prefix("usemtl ") {
+            let name = name.trim();
+            current_material = mesh.materials.iter().position(|material| material.name == name);
+            if current_material.is_none() {
+                let column = line.len() - line["usemtl ".len()..].trim_start().len() + 1;
+                error_at(column, ObjErrorKind::UnknownMaterial(name.to_string())).report(&mut warnings)?;
+            }
+        }
+
+        else if let Some(name) = line.strip_prefix("o ") {
+            start_group(&mut mesh.objects, name.trim(), mesh.faces.len());
+        }
+
+        else if let Some(name) = line.strip_prefix("g ") {
+            start_group(&mut mesh.groups, name.trim(), mesh.faces.len());
+        }
     }
-    obj_faces   
+
+    // close off the last object/group
+    let n_faces = mesh.faces.len();
+    for group in mesh.objects.last_mut().into_iter().chain(mesh.groups.last_mut()) {
+        group.faces.end = n_faces;
+    }
+
+    Ok(mesh)
+}
+
+// ends the current group (if any) and starts a new one at the given face
+fn start_group(groups: &mut Vec<ObjGroup>, name: &str, face: usize) {
+    if let Some(current) = groups.last_mut() {
+        current.faces.end = face;
+    }
+    groups.push(ObjGroup { name: name.to_string(), faces: face..face });
 }
 
 // face parsing
-// each face has 3 components divided by "/", corresponding to face, texture, and normal indices.
-// NOTE: indices in .obj files start from 1, hence why we must subtract 1 before using them.
-fn parse_face<'a>(
-    input: &'a str, 
-    vertex_coords: &Vec<Vec3>, 
-    texture_coords: &Vec<Vec3>,
-    normal_coords: &Vec<Vec3>
-) -> IResult<&'a str, ObjFace> {
+// a face has 3 or more vertices, each with up to 3 components divided by "/" (see FaceVertex)
+// each vertex comes with the text it was parsed from, for errors
+fn parse_face(input: &str) -> IResult<&str, Vec<(&str, FaceVertex)>> {
     let (input, _) = char('f')(input)?;
-    let (input, _) = multispace0(input)?;
+    let (input, _) = space1(input)?;
+    let (input, face_vertices) = separated_list1(space1, consumed(parse_face_vertex))(input)?;
+    let (input, _) = space0(input)?;
 
-    let (input, v1_vec) = separated_list0(tag("/"), map_res(digit1, str::parse::<usize>))(input)?;
-    let (input, _) = multispace0(input)?;
-    let (input, v2_vec) = separated_list0(tag("/"), map_res(digit1, str::parse::<usize>))(input)?;
-    let (input, _) = multispace0(input)?;
-    let (input, v3_vec) = separated_list0(tag("/"), map_res(digit1, str::parse::<usize>))(input)?;
-    
-    let vertices = [vertex_coords[v1_vec[0]-1], vertex_coords[v2_vec[0]-1], vertex_coords[v3_vec[0]-1]];
-    let texture_vertices = [texture_coords[v1_vec[1]-1], texture_coords[v2_vec[1]-1], texture_coords[v3_vec[1]-1]];
-    let normals = [normal_coords[v1_vec[2]-1], normal_coords[v2_vec[2]-1], normal_coords[v3_vec[2]-1]];
+    if face_vertices.len() < 3 {
+        return Err(nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::Count)));
+    }
+    Ok((input, face_vertices))
+}
+
+fn parse_face_vertex(input: &str) -> IResult<&str, FaceVertex> {
+    let (input, v) = i64(input)?;
+    let (input, vt) = opt(preceded(char('/'), opt(i64)))(input)?; // "v//vn" has an empty vt
+    let (input, vn) = opt(preceded(char('/'), i64))(input)?;
+    Ok((input, (v, vt.flatten(), vn)))
+}
 
-    Ok((input, ObjFace { vertices, texture_vertices, normals }))
+// look up a face's coords, add its vertices to the mesh, then triangulate it into the mesh's faces.
+// returns how many triangles it was split into.
+// if an index is out of range, returns which of the face's vertices it belongs to (and adds nothing).
+fn add_faces(
+    mesh: &mut Mesh,
+    vertex_ids: &mut HashMap<VertexKey, u32>,
+    face_vertices: &[FaceVertex],
+    vertex_coords: &[Vec3],
+    texture_coords: &[Vec3],
+    normal_coords: &[Vec3]
+) -> Result<usize, (usize, ObjErrorKind)> {
+    let mut indices = Vec::with_capacity(face_vertices.len());
+
+    for (i, &(v, vt, vn)) in face_vertices.iter().enumerate() {
+        let resolve = |idx: i64, len: usize| resolve_index(idx, len)
+            .ok_or((i, ObjErrorKind::IndexOutOfRange { index: idx, len }));
+        indices.push((
+            resolve(v, vertex_coords.len())?,
+            vt.map(|vt| resolve(vt, texture_coords.len())).transpose()?,
+            vn.map(|vn| resolve(vn, normal_coords.len())).transpose()?
+        ));
+    }
+
+    // fill in missing normals with the face's own normal
+    let vertices: Vec<Vec3> = indices.iter().map(|&(v, _, _)| vertex_coords[v]).collect();
+    let face_normal = polygon_normal(&vertices).normalize_or_zero();
+
+    let ids: Vec<u32> = indices
+        .iter()
+        .map(|&(v, vt, vn)| {
+            let texture_vertex = vt.map_or(DEFAULT_TEXTURE_VERTEX, |vt| texture_coords[vt]);
+            let normal = vn.map_or(face_normal, |vn| normal_coords[vn]);
+            let key = (v, vt, normal.to_array().map(f32::to_bits));
+            mesh.add_vertex(vertex_ids, key, vertex_coords[v], texture_vertex, normal)
+        })
+        .collect();
+
+    let triangles = triangulate(&vertices);
+    let n_triangles = triangles.len();
+    mesh.faces.extend(triangles.into_iter().map(|triangle| triangle.map(|i| ids[i])));
+    Ok(n_triangles)
+}
+
+// converts an .obj index into an index of the list it refers to (of length `len`)
+// NOTE: indices in .obj files start from 1, hence why we must subtract 1 before using them.
+fn resolve_index(idx: i64, len: usize) -> Option<usize> {
+    let resolved = if idx > 0 { idx - 1 } else { len as i64 + idx };
+    if idx == 0 || resolved < 0 || resolved >= len as i64 {
+        return None;
+    }
+    Some(resolved as usize)
+}
+
+// Newell's method; works for non-planar and concave polygons too.
+// not normalized, and zero if the polygon is degenerate.
+fn polygon_normal(vertices: &[Vec3]) -> Vec3 {
+    let mut normal = Vec3::ZERO;
+    for i in 0..vertices.len() {
+        let (current, next) = (vertices[i], vertices[(i + 1) % vertices.len()]);
+        normal += Vec3::new(
+            (current.y - next.y) * (current.z + next.z),
+            (current.z - next.z) * (current.x + next.x),
+            (current.x - next.x) * (current.y + next.y),
+        );
+    }
+    normal
+}
+
+// Split a polygon into triangles (as indices into `vertices`), keeping the polygon's winding.
+// Uses ear clipping, so concave polygons are handled; falls back to a fan if that gets stuck (eg degenerate polygons).
+fn triangulate(vertices: &[Vec3]) -> Vec<[usize; 3]> {
+    let normal = polygon_normal(vertices);
+    let mut remaining: Vec<usize> = (0..vertices.len()).collect();
+    let mut triangles = Vec::with_capacity(vertices.len() - 2);
+
+    if vertices.len() > 3 && normal != Vec3::ZERO {
+        let mut i = 0;
+        let mut since_last_ear = 0;
+        while remaining.len() > 3 && since_last_ear < remaining.len() {
+            let n = remaining.len();
+            let (prev, current, next) = (remaining[(i + n - 1) % n], remaining[i % n], remaining[(i + 1) % n]);
+            let (a, b, c) = (vertices[prev], vertices[current], vertices[next]);
+
+            // an ear is a convex corner with no other vertices inside it
+            let convex = (b - a).cross(c - b).dot(normal) > 0.0;
+            let is_ear = convex && remaining
+                .iter()
+                .filter(|&&other| other != prev && other != current && other != next)
+                .all(|&other| !in_triangle(vertices[other], a, b, c, normal));
+
+            if is_ear {
+                triangles.push([prev, current, next]);
+                remaining.remove(i % n);
+                since_last_ear = 0;
+            } else {
+                i += 1;
+                since_last_ear += 1;
+            }
+            i %= remaining.len();
+        }
+    }
+
+    // whatever is left (usually just a triangle) is split into a fan
+    for i in 1..remaining.len() - 1 {
+        triangles.push([remaining[0], remaining[i], remaining[i + 1]]);
+    }
+    triangles
+}
+
+// whether p is inside (or on an edge of) triangle abc, which faces `normal`
+fn in_triangle(p: Vec3, a: Vec3, b: Vec3, c: Vec3, normal: Vec3) -> bool {
+    (b - a).cross(p - a).dot(normal) >= 0.0
+        && (c - b).cross(p - b).dot(normal) >= 0.0
+        && (a - c).cross(p - c).dot(normal) >= 0.0
 }
 
 // vertex parsing
@@ -98,10 +453,11 @@ fn parse_vertex(input: &str) -> IResult<&str, Vec3> {
 fn parse_texture(input: &str) -> IResult<&str, Vec3> {
     let (input, _) = tag("vt")(input)?;
     let (input, _) = multispace0(input)?;
-    let (input, (x, _, y, _, z)) = tuple((float, space1, float, space1, float))(input)?;
-    
-    Ok((input, Vec3 { x, y, z })) // Note: z seems to always be 0, but we'll store it anyway just in case
-}   
+    let (input, (x, _, y)) = tuple((float, space1, float))(input)?;
+    let (input, z) = opt(preceded(space1, float))(input)?; // w is optional, and defaults to 0
+
+    Ok((input, Vec3 { x, y, z: z.unwrap_or(0.0) }))
+}
 
 // normal vertex parsing
 fn parse_normal(input: &str) -> IResult<&str, Vec3> {
@@ -110,4 +466,4 @@ fn parse_normal(input: &str) -> IResult<&str, Vec3> {
     let (input, (x, _, y, _, z)) = tuple((float, space1, float, space1, float))(input)?;
 
     Ok((input, Vec3 { x, y, z }))
-}
\ No newline at end of file
+}
@@ -0,0 +1,87 @@
+use crate::tgaimage::*;
+use crate::shaders::{OutlineShader, Shader};
+use crate::rasterizer::{draw_triangle, CullMode, DrawStats, RasterState, StencilOp, StencilState};
+use crate::framebuffer::{CompareFunc, Framebuffer};
+use crate::transform::Transform;
+use crate::obj::Mesh;
+use std::fmt::Debug;
+use glam::*;
+
+/// The stencil bit that marks selected pixels.
+pub const SELECTION_BIT: u8 = 1;
+
+/// What a selection outline looks like.
+#[derive(Clone, Copy, Debug)]
+pub struct Outline {
+    pub color: RGBA,
+    pub width: f32 // in pixels
+}
+
+impl Default for Outline {
+    fn default() -> Self {
+        Outline { color: RGBA { r: 255, g: 160, b: 0, a: 255 }, width: 3.0 }
+    }
+}
+
+/// Draws `mesh`'s `faces` (indices into it, eg a selected object's) with `shader`, then outlines them, on top of everything else.
+///
+/// This takes two passes:
+/// 1. the faces get drawn as usual, but also set SELECTION_BIT in the stencil wherever they're visible.
+///    The depth test also passes equal depths, so faces that are already in the framebuffer can be drawn again (ie selected later on).
+/// 2. the faces get drawn again, pushed outwards by the outline's width, in the outline's color,
+///    but only where the stencil doesn't have SELECTION_BIT, which leaves the rim around them.
+///    It ignores (and doesn't write) depth, so the outline shows through whatever is in front of it.
+#[allow(clippy::too_many_arguments)]
+pub fn draw_selection_outline<T, S>(
+    framebuffer: &mut Framebuffer<T>,
+    shader: &mut S,
+    mesh: &Mesh,
+    faces: &[usize],
+    state: &RasterState,
+    transform: &Transform,
+    outline: &Outline,
+    stats: &mut DrawStats
+)
+where
+    T: ColorSpace + Copy + Debug,
+    S: Shader<T> {
+
+    let depth_compare = framebuffer.depth.compare;
+
+    // mark the selection
+    let mark_state = RasterState {
+        stencil: Some(StencilState {
+            reference: SELECTION_BIT,
+            write_mask: SELECTION_BIT,
+            pass: StencilOp::Replace,
+            ..StencilState::default()
+        }),
+        ..*state
+    };
+    framebuffer.depth.compare = depth_compare.or_equal();
+    for &idx in faces {
+        let (clip_coords, varying) = shader.vertex(mesh.face(idx));
+        draw_triangle(framebuffer, shader, &varying, &mark_state, transform, clip_coords, stats);
+    }
+
+    // outline it, around the marked pixels
+    let outline_state = RasterState {
+        cull_mode: CullMode::None, // the back of the pushed out shell makes up the outline too
+        depth_write: false,
+        stencil: Some(StencilState {
+            compare: CompareFunc::NotEqual,
+            reference: SELECTION_BIT,
+            read_mask: SELECTION_BIT,
+            ..StencilState::default()
+        }),
+        ..*state
+    };
+    let mut outline_shader = OutlineShader::new(*transform, outline.color, outline.width);
+    framebuffer.depth.compare = CompareFunc::Always;
+    for &idx in faces {
+        let (clip_coords, varying) = Shader::<T>::vertex(&mut outline_shader, mesh.face(idx));
+        draw_triangle(framebuffer, &outline_shader, &varying, &outline_state, transform, clip_coords, stats);
+    }
+
+    framebuffer.depth.compare = depth_compare;
+}
@@ -0,0 +1,309 @@
+// A small self-contained PNG encoder (8 bits per channel, no interlacing).
+// Image data is compressed with zlib, using deflate with fixed Huffman codes + LZ77.
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+/// PNG color types we can write.
+#[derive(Clone, Copy, Debug)]
+pub enum PngColorType {
+    Grayscale = 0,
+    Rgb = 2,
+    Rgba = 6,
+}
+
+impl PngColorType {
+    fn channels(&self) -> usize {
+        match self {
+            PngColorType::Grayscale => 1,
+            PngColorType::Rgb => 3,
+            PngColorType::Rgba => 4,
+        }
+    }
+}
+
+/// Encodes a PNG file, given the image rows from top to bottom (each `width * channels` bytes).
+pub fn encode_png<'a>(width: usize, height: usize, color_type: PngColorType, rows: impl Iterator<Item = &'a [u8]>) -> Vec<u8> {
+    let mut png = SIGNATURE.to_vec();
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend((width as u32).to_be_bytes());
+    ihdr.extend((height as u32).to_be_bytes());
+    ihdr.extend([8, color_type as u8, 0, 0, 0]); // bit depth, color type, compression, filter, interlace
+    write_chunk(&mut png, b"IHDR", &ihdr);
+
+    let filtered = filter_rows(width * color_type.channels(), color_type.channels(), rows);
+    write_chunk(&mut png, b"IDAT", &zlib_compress(&filtered));
+    write_chunk(&mut png, b"IEND", &[]);
+
+    png
+}
+
+fn write_chunk(png: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    png.extend((data.len() as u32).to_be_bytes());
+    png.extend(chunk_type);
+    png.extend(data);
+    let crc = crc32(chunk_type.iter().chain(data));
+    png.extend(crc.to_be_bytes());
+}
+
+///// Filtering
+
+// Filter each row, choosing whichever filter gives the smallest sum of absolute differences (as libpng does).
+// Each filtered row is prefixed by its filter type.
+fn filter_rows<'a>(row_length: usize, bpp: usize, rows: impl Iterator<Item = &'a [u8]>) -> Vec<u8> {
+    let mut filtered = Vec::new();
+    let mut prev_row = vec![0u8; row_length];
+    let mut candidate = vec![0u8; row_length];
+    let mut best = vec![0u8; row_length];
+
+    for row in rows {
+        let mut best_score = u64::MAX;
+        let mut best_type = 0;
+        for filter_type in 0..5u8 {
+            for i in 0..row_length {
+                let a = if i >= bpp { row[i - bpp] } else { 0 };
+                let b = prev_row[i];
+                let c = if i >= bpp { prev_row[i - bpp] } else { 0 };
+                let predictor = match filter_type {
+                    0 => 0,
+                    1 => a,
+                    2 => b,
+                    3 => ((a as u16 + b as u16) / 2) as u8,
+                    _ => paeth(a, b, c)
+                };
+                candidate[i] = row[i].wrapping_sub(predictor);
+            }
+            let score = candidate.iter().map(|&x| (x as i8).unsigned_abs() as u64).sum();
+            if score < best_score {
+                best_score = score;
+                best_type = filter_type;
+                best.copy_from_slice(&candidate);
+            }
+        }
+        filtered.push(best_type);
+        filtered.extend_from_slice(&best);
+        prev_row.copy_from_slice(row);
+    }
+
+    filtered
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = ((p - a as i16).abs(), (p - b as i16).abs(), (p - c as i16).abs());
+    if pa <= pb && pa <= pc { a } else if pb <= pc { b } else { c }
+}
+
+///// Checksums
+
+fn crc32<'a>(bytes: impl Iterator<Item = &'a u8>) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in bytes.chunks(5552) { // largest n such that the sums can't overflow before the modulo
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+///// zlib + deflate
+
+const WINDOW_SIZE: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const MAX_CHAIN: usize = 64; // how many earlier positions to try per match; trades speed for compression
+const HASH_SIZE: usize = 1 << 15;
+
+const LENGTH_BASES: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA_BITS: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASES: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const DISTANCE_EXTRA_BITS: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+/// Wraps data in a zlib stream.
+/// Uses a single deflate block with fixed Huffman codes, falling back to stored blocks if that doesn't compress.
+pub fn zlib_compress(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01]; // 32K window, deflate, no dictionary, fastest-level hint
+
+    let compressed = deflate_fixed(data);
+    if compressed.len() < data.len() + data.len() / 65535 * 5 + 5 {
+        out.extend(compressed);
+    } else {
+        out.extend(deflate_stored(data));
+    }
+
+    out.extend(adler32(data).to_be_bytes());
+    out
+}
+
+fn deflate_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / 65535 * 5 + 5);
+    let mut chunks = data.chunks(65535).peekable();
+    if chunks.peek().is_none() {
+        out.extend([1, 0, 0, 0xFF, 0xFF]); // a single, empty, final block
+    }
+    while let Some(chunk) = chunks.next() {
+        out.push(chunks.peek().is_none() as u8); // BFINAL, BTYPE = 00
+        out.extend((chunk.len() as u16).to_le_bytes());
+        out.extend((!(chunk.len() as u16)).to_le_bytes());
+        out.extend(chunk);
+    }
+    out
+}
+
+fn deflate_fixed(data: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+    writer.write_bits(1, 1); // BFINAL
+    writer.write_bits(1, 2); // BTYPE = 01 (fixed Huffman)
+
+    let mut chains = HashChains::new();
+    let mut i = 0;
+    while i < data.len() {
+        let (length, distance) = chains.longest_match(data, i);
+        if length >= MIN_MATCH {
+            writer.write_length(length);
+            writer.write_distance(distance);
+            for j in i..i + length {
+                chains.insert(data, j);
+            }
+            i += length;
+        } else {
+            writer.write_literal(data[i] as u16);
+            chains.insert(data, i);
+            i += 1;
+        }
+    }
+
+    writer.write_literal(256); // end of block
+    writer.finish()
+}
+
+// For each position, links to the previous position in the window whose next 3 bytes hash the same.
+struct HashChains {
+    head: Vec<usize>,
+    prev: Vec<usize>,
+}
+
+impl HashChains {
+    fn new() -> Self {
+        HashChains { head: vec![usize::MAX; HASH_SIZE], prev: vec![usize::MAX; WINDOW_SIZE] }
+    }
+
+    fn hash(data: &[u8], i: usize) -> usize {
+        ((data[i] as usize) << 10 ^ (data[i + 1] as usize) << 5 ^ data[i + 2] as usize) & (HASH_SIZE - 1)
+    }
+
+    fn insert(&mut self, data: &[u8], i: usize) {
+        if i + MIN_MATCH <= data.len() {
+            let h = Self::hash(data, i);
+            self.prev[i % WINDOW_SIZE] = self.head[h];
+            self.head[h] = i;
+        }
+    }
+
+    // returns (length, distance) of the longest match for the data at i
+    fn longest_match(&self, data: &[u8], i: usize) -> (usize, usize) {
+        let (mut best_length, mut best_distance) = (0, 0);
+        if i + MIN_MATCH > data.len() {
+            return (best_length, best_distance);
+        }
+
+        let max_length = MAX_MATCH.min(data.len() - i);
+        let mut candidate = self.head[Self::hash(data, i)];
+        let mut chain = 0;
+        while candidate != usize::MAX && i - candidate <= WINDOW_SIZE && chain < MAX_CHAIN {
+            let length = data[candidate..candidate + max_length]
+                .iter()
+                .zip(&data[i..i + max_length])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if length > best_length {
+                best_length = length;
+                best_distance = i - candidate;
+                if length == max_length {
+                    break;
+                }
+            }
+            // entries get overwritten as the window slides, so stop once the chain stops going backwards
+            let next = self.prev[candidate % WINDOW_SIZE];
+            if next == usize::MAX || next >= candidate {
+                break;
+            }
+            candidate = next;
+            chain += 1;
+        }
+
+        (best_length, best_distance)
+    }
+}
+
+// Writes bits LSB-first, as deflate expects.
+struct BitWriter {
+    out: Vec<u8>,
+    buffer: u64,
+    n_bits: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        BitWriter { out: Vec::new(), buffer: 0, n_bits: 0 }
+    }
+
+    fn write_bits(&mut self, bits: u32, n: u32) {
+        self.buffer |= (bits as u64) << self.n_bits;
+        self.n_bits += n;
+        while self.n_bits >= 8 {
+            self.out.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.n_bits -= 8;
+        }
+    }
+
+    // Huffman codes are packed MSB-first, so they must be reversed
+    fn write_code(&mut self, code: u32, n: u32) {
+        self.write_bits(code.reverse_bits() >> (32 - n), n);
+    }
+
+    // literal/length alphabet with fixed codes (RFC 1951, 3.2.6)
+    fn write_literal(&mut self, symbol: u16) {
+        let symbol = symbol as u32;
+        match symbol {
+            0..=143 => self.write_code(0x30 + symbol, 8),
+            144..=255 => self.write_code(0x190 + symbol - 144, 9),
+            256..=279 => self.write_code(symbol - 256, 7),
+            _ => self.write_code(0xC0 + symbol - 280, 8),
+        }
+    }
+
+    fn write_length(&mut self, length: usize) {
+        let idx = LENGTH_BASES.iter().rposition(|&base| base as usize <= length).unwrap();
+        self.write_literal(257 + idx as u16);
+        self.write_bits((length - LENGTH_BASES[idx] as usize) as u32, LENGTH_EXTRA_BITS[idx] as u32);
+    }
+
+    fn write_distance(&mut self, distance: usize) {
+        let idx = DISTANCE_BASES.iter().rposition(|&base| base as usize <= distance).unwrap();
+        self.write_code(idx as u32, 5);
+        self.write_bits((distance - DISTANCE_BASES[idx] as usize) as u32, DISTANCE_EXTRA_BITS[idx] as u32);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.n_bits > 0 {
+            self.out.push(self.buffer as u8);
+        }
+        self.out
+    }
+}
@@ -1,58 +1,670 @@
 use crate::tgaimage::*;
 use crate::shaders::Shader;
+use crate::clipping::{clip_triangle, GUARD_BAND};
+use crate::transform::Transform;
+use crate::obj::Mesh;
+use crate::framebuffer::{CompareFunc, Framebuffer, MAX_COLOR_TARGETS};
 use std::fmt::Debug;
 use glam::*;
 
 
-// Triangle rasterization function with depth buffer + texture + perspective etc
+/// How attributes get interpolated across a triangle, ie what barycentric coords the fragment shader receives.
+/// `Affine` interpolates linearly in screen space, which is wrong under perspective; it's kept for comparison renders.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interpolation {
+    PerspectiveCorrect,
+    Affine
+}
+
+/// Which triangles get discarded before rasterization, depending on which way they face.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CullMode {
+    None,
+    Back,
+    Front
+}
+
+/// Which winding order (as seen on screen) makes a triangle front-facing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrontFace {
+    CounterClockwise,
+    Clockwise
+}
+
+/// How a fragment's color (src) gets combined with what's already in the pixel (dst), like OpenGL's blend functions.
+/// Alpha comes from `ColorSpace::alpha`, so colorspaces without an alpha channel count as opaque.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlendMode {
+    Alpha,         // src*a + dst*(1 - a), ie (SRC_ALPHA, ONE_MINUS_SRC_ALPHA)
+    Additive,      // src + dst, ie (ONE, ONE)
+    Premultiplied, // src + dst*(1 - a), for colors already multiplied by their alpha, ie (ONE, ONE_MINUS_SRC_ALPHA)
+    Multiply       // src*dst, ie (DST_COLOR, ZERO)
+}
+
+impl BlendMode {
+    /// Blends `src` over `dst`, channel by channel (alpha included, like OpenGL does by default).
+    pub fn blend<T: ColorSpace>(self, src: &T, dst: &T) -> T {
+        let alpha = src.alpha();
+        let channels = src.to_vec()
+            .into_iter()
+            .zip(dst.to_vec())
+            .map(|(s, d)| {
+                let (s, d) = (s as f32 / 255.0, d as f32 / 255.0);
+                let blended = match self {
+                    BlendMode::Alpha => s*alpha + d*(1.0 - alpha),
+                    BlendMode::Additive => s + d,
+                    BlendMode::Premultiplied => s + d*(1.0 - alpha),
+                    BlendMode::Multiply => s*d
+                };
+                (blended.clamp(0.0, 1.0) * 255.0).round() as u8
+            })
+            .collect();
+
+        let mut color = T::new();
+        color.from_vec(channels).unwrap();
+        color
+    }
+}
+
+/// What happens to a pixel's stencil value, depending on how its fragment did in the stencil + depth tests.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StencilOp {
+    Keep,
+    Zero,
+    Replace,  // with the reference value
+    Incr,     // clamped to 255
+    IncrWrap,
+    Decr,     // clamped to 0
+    DecrWrap,
+    Invert
+}
+
+impl StencilOp {
+    fn apply(self, value: u8, reference: u8) -> u8 {
+        match self {
+            StencilOp::Keep => value,
+            StencilOp::Zero => 0,
+            StencilOp::Replace => reference,
+            StencilOp::Incr => value.saturating_add(1),
+            StencilOp::IncrWrap => value.wrapping_add(1),
+            StencilOp::Decr => value.saturating_sub(1),
+            StencilOp::DecrWrap => value.wrapping_sub(1),
+            StencilOp::Invert => !value
+        }
+    }
+}
+
+/// Stencil testing, like OpenGL's: a fragment passes if `(reference & read_mask) <compare> (stencil & read_mask)`.
+/// Then, depending on the stencil + depth tests, one of the ops updates the stencil value (only the bits in `write_mask`).
+#[derive(Clone, Copy, Debug)]
+pub struct StencilState {
+    pub compare: CompareFunc,
+    pub reference: u8,
+    pub read_mask: u8,
+    pub write_mask: u8,
+    pub fail: StencilOp,       // the stencil test fails
+    pub depth_fail: StencilOp, // the stencil test passes, but the depth test fails
+    pub pass: StencilOp        // both pass (and the fragment isn't discarded)
+}
+
+impl Default for StencilState {
+    // passes everything, and leaves the stencil alone
+    fn default() -> Self {
+        StencilState {
+            compare: CompareFunc::Always,
+            reference: 0,
+            read_mask: 0xff,
+            write_mask: 0xff,
+            fail: StencilOp::Keep,
+            depth_fail: StencilOp::Keep,
+            pass: StencilOp::Keep
+        }
+    }
+}
+
+impl StencilState {
+    pub fn test(&self, stencil: u8) -> bool {
+        self.compare.passes(self.reference & self.read_mask, stencil & self.read_mask)
+    }
+
+    // applies an op to a stencil value, only touching the bits in write_mask
+    pub fn update(&self, op: StencilOp, stencil: &mut u8) {
+        let updated = op.apply(*stencil, self.reference);
+        *stencil = (*stencil & !self.write_mask) | (updated & self.write_mask);
+    }
+}
+
+/// Per-draw configuration of the rasterizer.
+#[derive(Clone, Copy, Debug)]
+pub struct RasterState {
+    pub interpolation: Interpolation,
+    pub guard_band: Option<f32>, // see `clip_triangle`
+    pub cull_mode: CullMode,
+    pub front_face: FrontFace,
+    pub blend: Option<BlendMode>, // None overwrites the pixel
+    pub depth_write: bool, // whether fragments that pass the depth test update the z-buffer
+    pub stencil: Option<StencilState> // None skips stencil testing
+}
+
+impl Default for RasterState {
+    // like OpenGL, nothing is culled, and counter-clockwise triangles are front-facing
+    fn default() -> Self {
+        RasterState {
+            interpolation: Interpolation::PerspectiveCorrect,
+            guard_band: Some(GUARD_BAND),
+            cull_mode: CullMode::None,
+            front_face: FrontFace::CounterClockwise,
+            blend: None,
+            depth_write: true,
+            stencil: None
+        }
+    }
+}
+
+impl RasterState {
+    /// Whether a triangle with this (screen space) signed area gets culled.
+    /// Positive area means counter-clockwise on screen; degenerate triangles are never culled (they cover nothing anyway).
+    pub fn is_culled(&self, signed_area: f32) -> bool {
+        if signed_area == 0.0 {
+            return false;
+        }
+        let counter_clockwise = signed_area > 0.0;
+        let front_facing = counter_clockwise == (self.front_face == FrontFace::CounterClockwise);
+        match self.cull_mode {
+            CullMode::None => false,
+            CullMode::Back => !front_facing,
+            CullMode::Front => front_facing
+        }
+    }
+
+    /// The output merge stage: writes a fragment that passed the depth test into its pixel (+ z-buffer), blending it if needed.
+    pub fn merge<T: ColorSpace + Copy>(&self, color: T, p_z: f32, pixel: &mut T, depth: &mut f32) {
+        if self.depth_write {
+            *depth = p_z;
+        }
+        self.merge_color(color, pixel);
+    }
+
+    /// Like `merge`, for just the color.
+    pub fn merge_color<T: ColorSpace + Copy>(&self, color: T, pixel: &mut T) {
+        *pixel = match self.blend {
+            Some(mode) => mode.blend(&color, pixel),
+            None => color
+        };
+    }
+}
+
+/// Sorts `mesh`'s `faces` (indices into it) back to front, by the view space depth of their centroids,
+/// as transparent faces must be drawn in that order to blend correctly.
+/// Returns the same indices, in drawing order.
+pub fn sort_back_to_front(transform: &Transform, mesh: &Mesh, faces: &[usize]) -> Vec<usize> {
+    let depth = |idx: usize| {
+        let centroid = mesh.faces[idx].iter().map(|&i| mesh.positions[i as usize]).sum::<Vec3>() / 3.0;
+        transform.view_transform(centroid).z
+    };
+
+    // the camera looks down -z, so the furthest face has the most negative z
+    let mut order: Vec<(f32, usize)> = faces.iter().map(|&idx| (depth(idx), idx)).collect();
+    order.sort_by(|a, b| a.0.total_cmp(&b.0));
+    order.into_iter().map(|(_, idx)| idx).collect()
+}
+
+/// Counts what happened to the triangles of a draw.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DrawStats {
+    pub triangles: usize, // submitted
+    pub culled: usize
+}
+
+
+// Draws a triangle into a framebuffer, given its clip coordinates + varyings (ie as returned by the vertex shader).
+// Clips it against the view frustum, then rasterizes whatever is left.
+pub fn draw_triangle<T, S>(
+    framebuffer: &mut Framebuffer<T>,
+    shader: &S, 
+    varying: &S::Varying,
+    state: &RasterState,
+    transform: &Transform,
+    clip_coords: [Vec4; 3], 
+    stats: &mut DrawStats
+)
+where 
+    T: ColorSpace + Copy + Debug,
+    S: Shader<T> {
+    
+    for (screen_coords, bary_coords) in screen_triangles(state, transform, clip_coords, stats) {
+        triangle(framebuffer, shader, varying, state, screen_coords, bary_coords);
+    }
+}
+
+// Clips a triangle (in clip coordinates), then takes whatever is left into screen space.
+// Returns the screen + barycentric coords of each resulting triangle (see `triangle`),
+// or nothing if the triangle gets culled (which is counted in `stats`).
+pub fn screen_triangles(
+    state: &RasterState,
+    transform: &Transform,
+    clip_coords: [Vec4; 3],
+    stats: &mut DrawStats
+) -> Vec<([Vec4; 3], [Vec3; 3])> {
+    stats.triangles += 1;
+
+    let triangles: Vec<_> = clip_triangle(clip_coords, state.guard_band)
+        .into_iter()
+        .map(|clipped| {
+            // like gl_FragCoord, w holds 1/w of the clip coords, for perspective-correct interpolation
+            let screen_coords = clipped.map(|v| 
+                transform
+                    .screen_transform(v.position)
+                    .extend(1.0 / v.position.w)
+            );
+            (screen_coords, clipped.map(|v| v.bary))
+        })
+        .collect();
+
+    // facing is decided by what's left on screen; clipping keeps the winding, so the pieces' areas all have the same sign
+    let signed_area: f32 = triangles.iter().map(|(screen_coords, _)| signed_area(screen_coords)).sum();
+    if state.is_culled(signed_area) {
+        stats.culled += 1;
+        return Vec::new();
+    }
+    triangles
+}
+
+// Twice the signed area of a triangle in screen space; positive if it's counter-clockwise
+// (the viewport doesn't flip y, so this is the same winding as in NDC).
+fn signed_area(screen_coords: &[Vec4; 3]) -> f32 {
+    let [v0, v1, v2] = screen_coords.map(|v| v.xy());
+    (v1 - v0).perp_dot(v2 - v0)
+}
+
+
+// Triangle rasterization function with depth buffer + texture + perspective etc, rendering into a framebuffer
+// `bary_coords` are the vertices' barycentric coords w.r.t. the triangle the shader was run on
+// (ie if this is a piece of a clipped triangle); these are what the fragment shader receives.
+// `screen_coords` w must be 1/w of the clip coords (or just 1.0, if there's no perspective).
 pub fn triangle<T, S>(
-    image: &mut Image<T>,
+    framebuffer: &mut Framebuffer<T>,
     shader: &S, 
-    screen_coords: [Vec3; 3], 
-    zbuffer: &mut Vec<f32>
+    varying: &S::Varying,
+    state: &RasterState,
+    screen_coords: [Vec4; 3], 
+    bary_coords: [Vec3; 3]
 )
 where 
     T: ColorSpace + Copy + Debug,
     S: Shader<T> {
 
-    // shrink bounding box to rasterize over
-    let mut bboxmin = Vec2::new(image.width as f32 - 1.0, image.height as f32 - 1.0);
+    let (bboxmin, bboxmax) = bounding_box(&screen_coords, framebuffer.width, framebuffer.height);
+    let width = framebuffer.width;
+
+    rasterize(state, &screen_coords, &bary_coords, bboxmin, bboxmax, |p_x, p_y, p_z, bc_clip| {
+        shade_fragment(shader, varying, state, bc_clip, p_z, framebuffer, p_x + p_y*width);
+    });
+}
+
+// The (inclusive) range of pixels to rasterize a triangle over, ie its bounding box clamped to the image
+pub fn bounding_box(screen_coords: &[Vec4; 3], width: usize, height: usize) -> (UVec2, UVec2) {
+    let mut bboxmin = Vec2::new(width as f32 - 1.0, height as f32 - 1.0);
     let mut bboxmax = Vec2::new(0.0, 0.0);
-    let clamp = Vec2::new(image.width as f32 - 1.0, image.height as f32 - 1.0);
+    let clamp = Vec2::new(width as f32 - 1.0, height as f32 - 1.0);
     
-    for vertex in &screen_coords {
-        bboxmin.x = f32::max(0.0, f32::min(bboxmin.x, vertex.x)) as f32;
-        bboxmin.y = f32::max(0.0, f32::min(bboxmin.y, vertex.y)) as f32;
+    for vertex in screen_coords {
+        bboxmin.x = f32::max(0.0, f32::min(bboxmin.x, vertex.x));
+        bboxmin.y = f32::max(0.0, f32::min(bboxmin.y, vertex.y));
     
-        bboxmax.x = f32::min(clamp.x, f32::max(bboxmax.x, vertex.x)) as f32;
-        bboxmax.y = f32::min(clamp.y, f32::max(bboxmax.y, vertex.y)) as f32;
+        bboxmax.x = f32::min(clamp.x, f32::max(bboxmax.x, vertex.x));
+        bboxmax.y = f32::min(clamp.y, f32::max(bboxmax.y, vertex.y));
     } 
 
+    (bboxmin.as_uvec2(), bboxmax.as_uvec2())
+}
+
+/// How many pixels the rasterizer tests at once (ie its SIMD lanes: a multiple of 4, see `coverage`).
+pub const LANES: usize = 4;
+const _: () = assert!(LANES.is_multiple_of(4), "LANES must be a multiple of 4");
+
+// vertices are snapped to 1/256th of a pixel
+const SUBPIXEL_BITS: u32 = 8;
+const SUBPIXEL_SCALE: f64 = (1 << SUBPIXEL_BITS) as f64;
+
+// Loops over the pixels of a triangle within [bboxmin, bboxmax] (inclusive),
+// calling `fragment` with each one's x, y, z-value, and barycentric coords for the fragment shader.
+// This is shared by every rasterization path, so they all cover (and shade) exactly the same pixels.
+pub fn rasterize(
+    state: &RasterState,
+    screen_coords: &[Vec4; 3],
+    bary_coords: &[Vec3; 3],
+    bboxmin: UVec2,
+    bboxmax: UVec2,
+    mut fragment: impl FnMut(usize, usize, f32, Vec3)
+) {
+    rasterize_coverage(screen_coords, bboxmin, bboxmax, |p_x, p_y, bc_screen| {
+        let (p_z, bc_clip) = interpolate(state, screen_coords, bary_coords, bc_screen);
+        fragment(p_x, p_y, p_z, bc_clip);
+    });
+}
+
+// The coverage half of `rasterize`: calls `covered` with the x, y + screen space barycentric coords of each pixel inside the triangle.
+//
+// Uses edge functions (see `Edge`), stepped incrementally from pixel to pixel, and tests a block of LANES pixels at a time.
+// Pixels are sampled at their centres, and the top-left rule decides who owns pixels that lie exactly on an edge,
+// so triangles sharing an edge never leave gaps or draw a pixel twice.
+//
+// Pixel centres are all a whole number of pixels (ie SUBPIXEL_SCALE sub-pixels) apart, so each edge function's value at them
+// is q * SUBPIXEL_SCALE + r, where r is the same for the whole triangle and q steps by the edge's `a` + `b` from pixel to pixel.
+// Only q's sign matters for the inside test, so blocks get tested on q, as i32s.
+pub fn rasterize_coverage(
+    screen_coords: &[Vec4; 3],
+    bboxmin: UVec2,
+    bboxmax: UVec2,
+    mut covered: impl FnMut(usize, usize, Vec3)
+) {
+    let Some((edges, inv_area)) = setup_edges(screen_coords) else {
+        return;
+    };
+
+    // how much each edge's q changes when moving one pixel right/up, and across a block's lanes
+    let step_x = edges.map(|edge| edge.a as i64);
+    let step_y = edges.map(|edge| edge.b as i64);
+    let lane_steps: [[i32; LANES]; 3] = std::array::from_fn(|i| std::array::from_fn(|lane| (step_x[i] * lane as i64) as i32));
+
+    // edge functions (+ bias) at the centre of the bounding box's first pixel, split into q + r
+    let start = (bboxmin.as_dvec2() + 0.5) * SUBPIXEL_SCALE;
+    let values = edges.map(|edge| (edge.at(start) + edge.bias) as i64);
+    let mut row = values.map(|value| value.div_euclid(SUBPIXEL_SCALE as i64));
+    let r: [i64; 3] = std::array::from_fn(|i| values[i].rem_euclid(SUBPIXEL_SCALE as i64) - edges[i].bias as i64);
+
+    for p_y in bboxmin.y as usize ..= bboxmax.y as usize {
+        let mut block = row;
+
+        for block_x in (bboxmin.x as usize ..= bboxmax.x as usize).step_by(LANES) {
+            // visit each covered lane, lowest first
+            let mut mask = coverage(block, &lane_steps);
+            while mask != 0 {
+                let lane = mask.trailing_zeros() as usize;
+                mask &= mask - 1;
+                let p_x = block_x + lane;
+                if p_x > bboxmax.x as usize {
+                    break;
+                }
+
+                // (back to the unbiased edge functions, ie unnormalized barycentric coords)
+                let bc: [f64; 3] = std::array::from_fn(|i| ((block[i] + step_x[i] * lane as i64) * SUBPIXEL_SCALE as i64 + r[i]) as f64);
+                covered(p_x, p_y, (DVec3::from_array(bc) * inv_area).as_vec3());
+            }
+
+            // step to the next block
+            for (q, step) in block.iter_mut().zip(step_x) {
+                *q += step * LANES as i64;
+            }
+        }
+
+        // step to the next row
+        for (q, step) in row.iter_mut().zip(step_y) {
+            *q += step;
+        }
+    }
+}
+
+// Like `rasterize`, but for multisampling: each pixel is tested at every offset of `pattern` (in pixels, from its corner).
+// Calls `fragment` once per pixel with any covered samples, with its x, y, coverage (bit i set if sample i is covered),
+// each sample's z-value (only meaningful if covered), and barycentric coords for the fragment shader.
+//
+// The fragment is shaded at the pixel centre if that's inside the triangle, otherwise at the centroid of the covered samples,
+// so attributes are never extrapolated past the triangle's edges.
+#[allow(clippy::too_many_arguments)]
+pub fn rasterize_multisample(
+    state: &RasterState,
+    screen_coords: &[Vec4; 3],
+    bary_coords: &[Vec3; 3],
+    bboxmin: UVec2,
+    bboxmax: UVec2,
+    pattern: &[Vec2],
+    mut fragment: impl FnMut(usize, usize, u32, &[f32], Vec3)
+) {
+    assert!(pattern.len() <= MAX_SAMPLES, "at most {MAX_SAMPLES} samples per pixel");
+    let Some((edges, inv_area)) = setup_edges(screen_coords) else {
+        return;
+    };
+
+    let step_x = edges.map(|edge| edge.a * SUBPIXEL_SCALE);
+    let step_y = edges.map(|edge| edge.b * SUBPIXEL_SCALE);
+
+    // edge functions (+ bias) at each sample of the bounding box's first pixel
+    let mut rows: [[f64; 3]; MAX_SAMPLES] = [[0.0; 3]; MAX_SAMPLES];
+    for (row, offset) in rows.iter_mut().zip(pattern) {
+        let start = (bboxmin.as_dvec2() + offset.as_dvec2()) * SUBPIXEL_SCALE;
+        *row = edges.map(|edge| edge.at(start) + edge.bias);
+    }
+
+    let z = Vec3::new(screen_coords[0].z, screen_coords[1].z, screen_coords[2].z);
+    let mut depths = [0.0; MAX_SAMPLES];
+
+    for p_y in bboxmin.y as usize ..= bboxmax.y as usize {
+        for (dx, p_x) in (bboxmin.x as usize ..= bboxmax.x as usize).enumerate() {
+            let mut coverage: u32 = 0;
+            let mut centroid = DVec3::ZERO;
+            for (i, row) in rows[..pattern.len()].iter().enumerate() {
+                let e: [f64; 3] = std::array::from_fn(|j| row[j] + step_x[j] * dx as f64);
+                if e[0].min(e[1]).min(e[2]) >= 0.0 {
+                    let bc_sample = DVec3::new(e[0] - edges[0].bias, e[1] - edges[1].bias, e[2] - edges[2].bias) * inv_area;
+                    coverage |= 1 << i;
+                    centroid += bc_sample;
+                    depths[i] = z.dot(bc_sample.as_vec3());
+                }
+            }
+            if coverage == 0 {
+                continue;
+            }
+
+            // screen space barycentric coords are affine, so the centroid's are the mean of the samples'
+            let centre = (DVec2::new(p_x as f64, p_y as f64) + 0.5) * SUBPIXEL_SCALE;
+            let bc_centre = DVec3::new(edges[0].at(centre), edges[1].at(centre), edges[2].at(centre)) * inv_area;
+            let bc_screen = if bc_centre.min_element() >= 0.0 {
+                bc_centre
+            } else {
+                centroid / coverage.count_ones() as f64
+            };
+
+            let (_, bc_clip) = interpolate(state, screen_coords, bary_coords, bc_screen.as_vec3());
+            fragment(p_x, p_y, coverage, &depths[..pattern.len()], bc_clip);
+        }
+
+        for row in rows.iter_mut() {
+            for (value, step) in row.iter_mut().zip(step_y) {
+                *value += step;
+            }
+        }
+    }
+}
+
+/// The most samples per pixel `rasterize_multisample` supports.
+pub const MAX_SAMPLES: usize = 8;
+
+// Snaps a triangle's vertices to fixed point (in sub-pixel units), and sets up its edge functions.
+// Returns them, with 1 / (twice the area) to normalize them into barycentric coords; or None if the triangle is degenerate.
+fn setup_edges(screen_coords: &[Vec4; 3]) -> Option<([Edge; 3], f64)> {
+    let v = screen_coords.map(|v| (v.xy().as_dvec2() * SUBPIXEL_SCALE).round());
+
+    // twice the signed area; zero means the triangle is degenerate (ie a line or point), so it covers nothing
+    let area = Edge::new(v[0], v[1], 1.0).at(v[2]);
+    if area == 0.0 || !area.is_finite() {
+        return None;
+    }
+
+    // each edge's function is the (unnormalized) barycentric coord of the opposite vertex
+    let edges = [Edge::new(v[1], v[2], area), Edge::new(v[2], v[0], area), Edge::new(v[0], v[1], area)];
+    Some((edges, 1.0 / area.abs()))
+}
+
+// Interpolates a point's z-value from its screen space barycentric coords,
+// and turns them into the barycentric coords the fragment shader receives.
+#[inline]
+fn interpolate(state: &RasterState, screen_coords: &[Vec4; 3], bary_coords: &[Vec3; 3], bc_screen: Vec3) -> (f32, Vec3) {
+    let p_z = screen_coords[0].z * bc_screen.x
+                + screen_coords[1].z * bc_screen.y
+                + screen_coords[2].z * bc_screen.z;
+
+    // correct the barycentric coords for perspective if needed,
+    // by interpolating them divided by w, then normalizing them back
+    let bc_clip = match state.interpolation {
+        Interpolation::PerspectiveCorrect => {
+            let bc = bc_screen * Vec3::new(screen_coords[0].w, screen_coords[1].w, screen_coords[2].w);
+            bc / (bc.x + bc.y + bc.z)
+        },
+        Interpolation::Affine => bc_screen
+    };
+
+    (p_z, bary_to_point(&bc_clip, bary_coords))
+}
+
+// Which pixels of a block are inside all 3 edges, as a bitmask (bit i for lane i),
+// given each edge's q at the block's first pixel, and how much it grows by each lane.
+//
+// q can be larger than an i32, but then it's further from 0 than any lane's step (as long as triangles are under 2^20 pixels across),
+// so clamping it keeps every lane's sign.
+// On x86_64, this tests 4 lanes per instruction with SSE2 (which every x86_64 cpu has): a lane is inside if none of its edges are negative,
+// ie if the sign bit of their OR is clear. Elsewhere, it's a plain loop.
+#[cfg(target_arch = "x86_64")]
+#[inline(always)]
+fn coverage(block: [i64; 3], lane_steps: &[[i32; LANES]; 3]) -> u32 {
+    use std::arch::x86_64::{__m128i, _mm_add_epi32, _mm_castsi128_ps, _mm_loadu_si128, _mm_movemask_ps, _mm_or_si128, _mm_set1_epi32};
+
+    let block = block.map(|q| q.clamp(-Q_LIMIT, Q_LIMIT) as i32);
+    let mut covered = 0;
+    for quad in (0..LANES).step_by(4) {
+        // SAFETY: SSE2 is always available on x86_64, and each load reads 4 i32s that are in bounds (LANES is a multiple of 4)
+        let outside = unsafe {
+            let [e0, e1, e2]: [__m128i; 3] = std::array::from_fn(|i| {
+                _mm_add_epi32(_mm_set1_epi32(block[i]), _mm_loadu_si128(lane_steps[i][quad..].as_ptr() as *const __m128i))
+            });
+            _mm_movemask_ps(_mm_castsi128_ps(_mm_or_si128(_mm_or_si128(e0, e1), e2)))
+        };
+        covered |= (!outside as u32 & 0b1111) << quad;
+    }
+    covered
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+#[inline(always)]
+fn coverage(block: [i64; 3], lane_steps: &[[i32; LANES]; 3]) -> u32 {
+    let block = block.map(|q| q.clamp(-Q_LIMIT, Q_LIMIT) as i32);
+    (0..LANES)
+        .filter(|&lane| (0..3).all(|i| block[i] + lane_steps[i][lane] >= 0))
+        .fold(0, |covered, lane| covered | 1 << lane)
+}
+
+// what `coverage` clamps q to, leaving room for the lanes' steps within an i32
+const Q_LIMIT: i64 = 1 << 30;
+
+// The edge function of the edge from `from` to `to`, ie E(p) = a*x + b*y + c,
+// which is twice the signed area of the triangle (from, to, p).
+// It's flipped for clockwise triangles (ie negative `area`), so that it's always positive inside the triangle.
+//
+// As vertices are snapped to integers (in sub-pixel units), every value is an integer too;
+// f64 represents them exactly (the guard band keeps them well below 2^53), so the inside test is exact.
+#[derive(Clone, Copy)]
+struct Edge {
+    a: f64,
+    b: f64,
+    c: f64,
+    bias: f64
+}
+
+impl Edge {
+    fn new(from: DVec2, to: DVec2, area: f64) -> Self {
+        let sign = area.signum();
+        let a = (from.y - to.y) * sign;
+        let b = (to.x - from.x) * sign;
+        let c = (from.x * to.y - from.y * to.x) * sign;
+
+        // top-left rule: a pixel centre exactly on the edge only counts as inside if this is a left edge (ie the inside is to its right),
+        // or a top edge (ie horizontal, with the inside below it); the bias makes E(p) = 0 fail the test otherwise.
+        let is_top_left = a > 0.0 || (a == 0.0 && b < 0.0);
+        Edge { a, b, c, bias: if is_top_left { 0.0 } else { -1.0 } }
+    }
+
+    fn at(&self, p: DVec2) -> f64 {
+        self.a * p.x + self.b * p.y + self.c
+    }
+}
+
+// The original rasterizer: computes barycentric coords from scratch (a cross product + division) for every pixel of the bounding box,
+// sampling pixels at their corners. It also drops triangles smaller than about half a pixel.
+// Superseded by `rasterize`; kept as a baseline for benchmarks.
+pub fn rasterize_barycentric(
+    state: &RasterState,
+    screen_coords: &[Vec4; 3],
+    bary_coords: &[Vec3; 3],
+    bboxmin: UVec2,
+    bboxmax: UVec2,
+    mut fragment: impl FnMut(usize, usize, f32, Vec3)
+) {
+    rasterize_barycentric_coverage(screen_coords, bboxmin, bboxmax, |p_x, p_y, bc_screen| {
+        let (p_z, bc_clip) = interpolate(state, screen_coords, bary_coords, bc_screen);
+        fragment(p_x, p_y, p_z, bc_clip);
+    });
+}
+
+// The coverage half of `rasterize_barycentric`, like `rasterize_coverage` is `rasterize`'s (kept for benchmarks too).
+pub fn rasterize_barycentric_coverage(
+    screen_coords: &[Vec4; 3],
+    bboxmin: UVec2,
+    bboxmax: UVec2,
+    mut covered: impl FnMut(usize, usize, Vec3)
+) {
+    let screen_xyz = screen_coords.map(|v| v.xyz());
+
     // loop over pixels within the bounding box 
-    for p_x in bboxmin.x as i32 .. bboxmax.x as i32 + 1 {
-        for p_y in bboxmin.y as i32 .. bboxmax.y as i32 + 1 {
-            let bc_screen = barycentric(&screen_coords, &Vec3::new(p_x as f32, p_y as f32, 0.0));
+    for p_x in bboxmin.x as usize ..= bboxmax.x as usize {
+        for p_y in bboxmin.y as usize ..= bboxmax.y as usize {
+            let bc_screen = barycentric(&screen_xyz, &Vec3::new(p_x as f32, p_y as f32, 0.0));
             if bc_screen.x < 0.0 || bc_screen.y < 0.0 || bc_screen.z < 0.0 { // check for valid baryometric coords
                 continue;
                 }
 
-            // check if current z-value is greater than last one (ie closer to "camera")
-            let p_z = screen_coords[0].z * bc_screen.x
-                        + screen_coords[1].z * bc_screen.y
-                        + screen_coords[2].z * bc_screen.z;
+            covered(p_x, p_y, bc_screen);
+        }
+    }
+}
 
-            if p_z > zbuffer[(p_x + p_y*image.width as i32) as usize] {
+// Stencil + depth tests a fragment against its pixel, then shades it + updates the pixel (in every color target) if it passes
+pub fn shade_fragment<T, S>(shader: &S, varying: &S::Varying, state: &RasterState, bary_coords: Vec3, p_z: f32, framebuffer: &mut Framebuffer<T>, idx: usize)
+where 
+    T: ColorSpace + Copy,
+    S: Shader<T> {
 
-                // instantiate a color and run it through shader
-                let mut texture_color = T::white();
-                let discard = shader.fragment(bc_screen, &mut texture_color);
+    // check the stencil first
+    if let Some(stencil) = &state.stencil {
+        if !stencil.test(framebuffer.stencil.data[idx]) {
+            stencil.update(stencil.fail, &mut framebuffer.stencil.data[idx]);
+            return;
+        }
+    }
 
-                // if don't discard, update zbuffer + set pixel
-                if !discard {
-                    zbuffer[(p_x + p_y*image.width as i32) as usize] = p_z;
-                    image.set(p_x as usize, p_y as usize, texture_color).unwrap();
-                }
-            }
+    // check if current z-value passes (ie by default, is greater than last one, so closer to "camera")
+    if !framebuffer.depth.test(idx, p_z) {
+        if let Some(stencil) = &state.stencil {
+            stencil.update(stencil.depth_fail, &mut framebuffer.stencil.data[idx]);
+        }
+        return;
+    }
+
+    // instantiate a color per target and run them through shader
+    let mut colors = [T::white(); MAX_COLOR_TARGETS];
+    let colors = &mut colors[..framebuffer.colors.len()];
+    let discard = shader.fragment_targets(varying, bary_coords, colors);
+
+    // if don't discard, update stencil + zbuffer (if enabled) + set (or blend into) pixels
+    if !discard {
+        if let Some(stencil) = &state.stencil {
+            stencil.update(stencil.pass, &mut framebuffer.stencil.data[idx]);
+        }
+        if state.depth_write {
+            framebuffer.depth.data[idx] = p_z;
+        }
+        for (target, &color) in framebuffer.colors.iter_mut().zip(colors.iter()) {
+            state.merge_color(color, &mut target.data[idx]);
         }
     }
 }
@@ -60,6 +672,7 @@ where
 
 // Calculate barycentric weights, given 3 vertices and a point
 // Pass in Vec3, but we only use x and y
+#[inline]
 fn barycentric(vertices: &[Vec3; 3], p: &Vec3) -> Vec3 {
     let a = Vec3::new(vertices[2].x - vertices[0].x, vertices[1].x - vertices[0].x, vertices[0].x - p.x);
     let b = Vec3::new(vertices[2].y - vertices[0].y, vertices[1].y - vertices[0].y, vertices[0].y - p.y);
@@ -78,10 +691,107 @@ fn barycentric(vertices: &[Vec3; 3], p: &Vec3) -> Vec3 {
 }
 
 // Convert barycentric coords into a point
+#[inline]
 pub fn bary_to_point(bc_coords: &Vec3, vertices: &[Vec3; 3]) -> Vec3 {
     Vec3::new(
         bc_coords.x*vertices[0].x + bc_coords.y*vertices[1].x + bc_coords.z*vertices[2].x,
         bc_coords.x*vertices[0].y + bc_coords.y*vertices[1].y + bc_coords.z*vertices[2].y,
         bc_coords.x*vertices[0].z + bc_coords.y*vertices[1].z + bc_coords.z*vertices[2].z,
     )
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transform::{initialize_transform, Projection};
+    use crate::obj::ObjFace;
+
+    // leaves every fragment white
+    struct FlatShader;
+
+    impl Shader<Grayscale> for FlatShader {
+        type Varying = ();
+
+        fn vertex(&mut self, obj_face: ObjFace) -> ([Vec4; 3], Self::Varying) {
+            (obj_face.vertices.map(|v| v.extend(1.0)), ())
+        }
+
+        fn fragment(&self, _varying: &Self::Varying, _bary_coords: Vec3, _color: &mut Grayscale) -> bool {
+            false
+        }
+    }
+
+    // shades one fragment into each pixel of a row, whose stencil starts off as `stencil`, and returns the stencil after
+    fn stencil_after(stencil: &[u8], state: StencilState, depth_compare: CompareFunc) -> Vec<u8> {
+        let mut framebuffer: Framebuffer<Grayscale> = Framebuffer::new(stencil.len(), 1, 1);
+        framebuffer.stencil.data.copy_from_slice(stencil);
+        framebuffer.depth.compare = depth_compare;
+        let state = RasterState { stencil: Some(state), ..RasterState::default() };
+        for idx in 0..stencil.len() {
+            shade_fragment(&FlatShader, &(), &state, Vec3::ONE / 3.0, 0.5, &mut framebuffer, idx);
+        }
+        framebuffer.stencil.data
+    }
+
+    fn rgba(r: u8, g: u8, b: u8, a: u8) -> RGBA {
+        RGBA { r, g, b, a }
+    }
+
+    #[test]
+    fn blend_modes() {
+        let src = rgba(255, 128, 0, 128); // alpha ~0.5
+        let dst = rgba(0, 128, 255, 255);
+
+        assert_eq!(BlendMode::Alpha.blend(&src, &dst).to_vec(), vec![128, 128, 127, 191]); // alpha gets blended too
+        assert_eq!(BlendMode::Additive.blend(&src, &dst).to_vec(), vec![255, 255, 255, 255]);
+        assert_eq!(BlendMode::Premultiplied.blend(&src, &dst).to_vec(), vec![255, 192, 127, 255]);
+        assert_eq!(BlendMode::Multiply.blend(&src, &dst).to_vec(), vec![0, 64, 0, 128]);
+
+        // without an alpha channel, src is opaque
+        let (src, dst) = (RGB { r: 200, g: 100, b: 0 }, RGB { r: 50, g: 50, b: 50 });
+        assert_eq!(BlendMode::Alpha.blend(&src, &dst).to_vec(), vec![200, 100, 0]);
+        assert_eq!(BlendMode::Premultiplied.blend(&src, &dst).to_vec(), vec![200, 100, 0]);
+    }
+
+    #[test]
+    fn sorts_back_to_front() {
+        // three triangles facing the camera, at z = 0, -1 and 1 (the camera is at z = 3)
+        let mesh = Mesh {
+            positions: [0.0, -1.0, 1.0]
+                .iter()
+                .flat_map(|&z| [Vec3::new(0.0, 0.0, z), Vec3::new(1.0, 0.0, z), Vec3::new(0.0, 1.0, z)])
+                .collect(),
+            faces: vec![[0, 1, 2], [3, 4, 5], [6, 7, 8]],
+            ..Default::default()
+        };
+        let transform = initialize_transform(100, 100, Vec3::new(0.0, 0.0, 3.0), Vec3::ZERO, Vec3::Y, Projection::default());
+
+        assert_eq!(sort_back_to_front(&transform, &mesh, &[0, 1, 2]), vec![1, 0, 2]);
+        // only the faces given, in drawing order
+        assert_eq!(sort_back_to_front(&transform, &mesh, &[2, 0]), vec![0, 2]);
+    }
+
+    #[test]
+    fn stencil_ops() {
+        let stencil = [0x00, 0xff, 0x0f, 0x81];
+
+        // every fragment passes, and wraps around past 255
+        let incr_wrap = StencilState { pass: StencilOp::IncrWrap, ..StencilState::default() };
+        assert_eq!(stencil_after(&stencil, incr_wrap, CompareFunc::Always), vec![0x01, 0x00, 0x10, 0x82]);
+
+        // every fragment fails the depth test, and stops at 0
+        let decr = StencilState { depth_fail: StencilOp::Decr, ..StencilState::default() };
+        assert_eq!(stencil_after(&stencil, decr, CompareFunc::Never), vec![0x00, 0xfe, 0x0e, 0x80]);
+
+        // only the low bits are compared (so only 0x81 passes), and only the high bits of those that fail get inverted
+        let invert = StencilState {
+            compare: CompareFunc::Equal,
+            reference: 0x01,
+            read_mask: 0x0f,
+            write_mask: 0xf0,
+            fail: StencilOp::Invert,
+            pass: StencilOp::Zero,
+            ..StencilState::default()
+        };
+        assert_eq!(stencil_after(&stencil, invert, CompareFunc::Always), vec![0xf0, 0x0f, 0xff, 0x01]);
+    }
+}
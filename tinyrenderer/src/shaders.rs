@@ -4,13 +4,21 @@ mod normal;
 mod normal_spec;
 mod tangent_normal;
 mod shadow;
+mod outline;
+mod deferred;
+mod ao_bake;
+mod pbr;
 
-pub use shader::Shader;
+pub use shader::{GeometryShader, LightingShader, OccludedShader, Shader};
 pub use gouraud::GouraudShader;
 pub use normal::NormalMappedShader;
 pub use normal_spec::NormalSpecularShader;
 pub use tangent_normal::TangentNormalShader;
 pub use shadow::{DepthShader, ShadowShader};
+pub use outline::OutlineShader;
+pub use deferred::{DeferredLightingShader, GBufferShader};
+pub use ao_bake::AoBakeShader;
+pub use pbr::PbrShader;
 
 
 
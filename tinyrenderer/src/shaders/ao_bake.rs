@@ -0,0 +1,72 @@
+use crate::{framebuffer::DepthBuffer, rasterizer::bary_to_point, Grayscale, ObjFace, Shader, Transform};
+use glam::*;
+
+// Ambient occlusion baking: draws a face into texture space (ie at its uv coords), where each texel checks
+// whether its point on the model is visible from the current direction, using a depth map rendered from there.
+// Writes 2 targets: whether the texel is visible (white) or not (black), and whether the direction counted at all (ie it's
+// in front of the surface), so the visible fraction of each texel's hemisphere can be accumulated over many directions.
+pub struct AoBakeShader {
+    uniform_depth_transform: Mat4, // object space -> the depth map's screen space
+    uniform_depth_map: DepthBuffer,
+    uniform_texture_size: Vec2,
+    uniform_bias: f32, // in depth map z-units
+    uniform_dir: Vec3 // the direction the depth map was rendered from (ie towards the viewer)
+}
+
+impl AoBakeShader {
+    pub fn new(depth_transform: &Transform, depth_map: DepthBuffer, texture_width: usize, texture_height: usize, bias: f32, dir: Vec3) -> Self {
+        AoBakeShader {
+            uniform_depth_transform: depth_transform.get_whole_transform(),
+            uniform_depth_map: depth_map,
+            uniform_texture_size: Vec2::new(texture_width as f32, texture_height as f32),
+            uniform_bias: bias,
+            uniform_dir: dir.normalize()
+        }
+    }
+}
+
+pub struct AoBakeVarying {
+    normals: [Vec3; 3],
+    coords: [Vec3; 3]
+}
+
+impl Shader<Grayscale> for AoBakeShader {
+    type Varying = AoBakeVarying;
+
+    // NOTE: returns texture space coords (with w = 1), which go straight to `rasterizer::triangle`, rather than clip coords.
+    // Rows go top down, like the images models read (see `Model`), so v is flipped.
+    fn vertex(&mut self, obj_face: ObjFace) -> ([Vec4; 3], AoBakeVarying) {
+        let texture_coords = obj_face.texture_vertices.map(|uv| {
+            Vec4::new(uv.x * self.uniform_texture_size.x, (1.0 - uv.y) * self.uniform_texture_size.y, 0.0, 1.0)
+        });
+        (texture_coords, AoBakeVarying { normals: obj_face.normals, coords: obj_face.vertices })
+    }
+
+    fn fragment(&self, varying: &AoBakeVarying, bary_coords: Vec3, color: &mut Grayscale) -> bool {
+        let mut colors = [*color; 2];
+        let discard = self.fragment_targets(varying, bary_coords, &mut colors);
+        *color = colors[0];
+        discard
+    }
+
+    fn fragment_targets(&self, varying: &AoBakeVarying, bary_coords: Vec3, colors: &mut [Grayscale]) -> bool {
+        // directions behind the surface don't count
+        let normal = bary_to_point(&bary_coords, &varying.normals).normalize_or_zero();
+        if normal.dot(self.uniform_dir) <= 0.0 {
+            return true;
+        }
+
+        // visible if nothing in the depth map is in front of it (greater z is closer)
+        let point = self.uniform_depth_transform.project_point3(bary_to_point(&bary_coords, &varying.coords));
+        let visible = (point.x >= 0.0 && point.y >= 0.0)
+            .then(|| self.uniform_depth_map.get(point.x as usize, point.y as usize))
+            .flatten()
+            .is_none_or(|depth| depth <= point.z + self.uniform_bias);
+
+        let targets = [Grayscale { i: if visible { 255 } else { 0 } }, Grayscale { i: 255 }];
+        for (color, target) in colors.iter_mut().zip(targets) {
+            *color = target;
+        }
+        false
+    }
+}
@@ -0,0 +1,116 @@
+use crate::{deferred::Surface, light::{accumulate_light, apply_light, Light, Occlusion}, rasterizer::bary_to_point, ColorSpace, Model, ObjFace, Transform};
+use glam::*;
+use super::shader::{GeometryShader, LightingShader, OccludedShader};
+
+// Geometry pass: fetches what normal + specular mapping needs into the G-buffer, without lighting it
+pub struct GBufferShader<T: ColorSpace + Copy> {
+    uniform_model: Model<T>,
+    uniform_transform: Transform
+}
+
+impl<T: ColorSpace + Copy> GBufferShader<T> {
+    pub fn new(model: Model<T>, transform: Transform) -> Self {
+        GBufferShader {
+            uniform_model: model,
+            uniform_transform: transform
+        }
+    }
+}
+
+pub struct GBufferVarying {
+    uv: [Vec3; 3],
+    normals: [Vec3; 3], // used if there's no normal map
+    material: Option<usize>,
+    coords: [Vec3; 3]
+}
+
+impl<T: ColorSpace + Copy> GeometryShader<T> for GBufferShader<T> {
+    type Varying = GBufferVarying;
+
+    fn vertex(&mut self, obj_face: ObjFace) -> ([Vec4; 3], GBufferVarying) {
+        let varying = GBufferVarying {
+            uv: obj_face.texture_vertices,
+            normals: obj_face.normals,
+            material: obj_face.material,
+            coords: obj_face.vertices
+        };
+        let transformed_face = obj_face.vertices.map(|v| self.uniform_transform.clip_transform(v));
+        (transformed_face, varying)
+    }
+
+    fn fragment(&self, varying: &GBufferVarying, bary_coords: Vec3) -> Option<Surface<T>> {
+        // compute actual coords for corresponding pixel in texture + specular + normal images 
+        let uv = bary_to_point(&bary_coords, &varying.uv).truncate();
+
+        // get normal of corresponding pixel (or the interpolated vertex normal, without a normal map)
+        let untransformed_normal = self.uniform_model
+            .get_normal(varying.material, uv)
+            .unwrap_or_else(|| bary_to_point(&bary_coords, &varying.normals));
+
+        let mut albedo = self.uniform_model.get_texture_color(varying.material, uv);
+        albedo.set_alpha(albedo.alpha() * self.uniform_model.get_alpha(varying.material, uv));
+
+        Some(Surface {
+            position: self.uniform_transform.view_transform(bary_to_point(&bary_coords, &varying.coords)),
+            normal: self.uniform_transform.view_normal_transform(untransformed_normal).normalize(),
+            geometric_normal: self.uniform_transform.view_normal_transform(bary_to_point(&bary_coords, &varying.normals)).normalize_or_zero(),
+            albedo,
+            specular: self.uniform_model.get_specularity(varying.material, uv),
+            specular_weight: self.uniform_model.get_specular_weight(varying.material),
+            ambient: self.uniform_model.get_ambient(varying.material),
+            occlusion: self.uniform_model.get_occlusion(varying.material, uv),
+            material: varying.material
+        })
+    }
+}
+
+// Lighting pass: the same lighting as NormalSpecularShader, once per pixel of the G-buffer
+pub struct DeferredLightingShader {
+    uniform_lights: Vec<Light>, // in view space
+    uniform_view_to_object: Mat4, // since shadow maps are in object space
+    uniform_occlusion: Occlusion // point lights' shadows + SSAO (which takes view space points, as that's what the G-buffer has)
+}
+
+impl DeferredLightingShader {
+    pub fn new(transform: Transform, lights: &[Light]) -> Self {
+        DeferredLightingShader {
+            uniform_lights: lights.iter().map(|light| light.view_transform(&transform)).collect(),
+            uniform_view_to_object: transform.model_view.inverse(),
+            uniform_occlusion: Occlusion::new(transform.viewport * transform.projection)
+        }
+    }
+}
+
+impl OccludedShader for DeferredLightingShader {
+    fn occlusion_mut(&mut self) -> &mut Occlusion {
+        &mut self.uniform_occlusion
+    }
+}
+
+impl<T: ColorSpace + Copy> LightingShader<T> for DeferredLightingShader {
+    fn shade(&self, surface: &Surface<T>, color: &mut T) {
+        let normal = surface.normal;
+
+        // sum up every light's diffuse light (normal lighting) + specular light ("highlight" from reflection of light), weighted
+        let diffuse_w = 1.0;
+        let spec_w = surface.specular_weight;
+        let object_point = self.uniform_view_to_object.transform_point3(surface.position);
+        let surface_normal = (Mat3::from_mat4(self.uniform_view_to_object).inverse().transpose() * surface.geometric_normal).normalize_or_zero();
+        let light = accumulate_light(
+            &self.uniform_lights,
+            surface.position,
+            |i| self.uniform_occlusion.shadows.visibility(i, object_point, surface_normal),
+            |light_dir| {
+                let diffuse_light = normal.dot(light_dir).max(0.0);
+                let reflection = (normal * (2.0 * normal.dot(light_dir)) - light_dir).normalize();
+                let specular_light = (reflection.z.max(0.0)).powf(surface.specular);
+                Vec3::splat(diffuse_w*diffuse_light + spec_w*specular_light)
+            }
+        );
+
+        // then use it + weighted ambient light to modify each color
+        let ambient_w = 5.0 * surface.occlusion * self.uniform_occlusion.ambient_occlusion(surface.position);
+        *color = surface.albedo;
+        apply_light(color, surface.ambient * ambient_w, light);
+    }
+}
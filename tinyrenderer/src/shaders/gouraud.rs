@@ -1,53 +1,62 @@
-use crate::{rasterizer::bary_to_point, ColorSpace, Model, ObjFace, Transform};
+use crate::{light::{accumulate_diffuse, apply_light, Light}, rasterizer::bary_to_point, ColorSpace, Model, ObjFace, Transform};
 use glam::*;
 use super::shader::Shader;
 
 // Gouraud shading with texture
 pub struct GouraudShader<T: ColorSpace + Copy> {
-    varying_intensity: [f32; 3],
-    varying_texture_coords: [Vec3; 3],
     uniform_model: Model<T>,
-    uniform_transform: Transform
+    uniform_transform: Transform,
+    uniform_lights: Vec<Light> // in view space
+}
+
+pub struct GouraudVarying {
+    intensity: [Vec3; 3], // per channel, since lights can be colored
+    uv: [Vec3; 3],
+    material: Option<usize>
 }
 
 impl<T: ColorSpace + Copy> GouraudShader<T> {
-    pub fn new(model: Model<T>, transform: Transform) -> Self {
+    pub fn new(model: Model<T>, transform: Transform, lights: &[Light]) -> Self {
         GouraudShader {
-            varying_intensity: [0.0, 0.0, 0.0],
-            varying_texture_coords: [Vec3::new(0.0, 0.0, 0.0); 3],
             uniform_model: model,
-            uniform_transform: transform
+            uniform_transform: transform,
+            uniform_lights: lights.iter().map(|light| light.view_transform(&transform)).collect()
         }
     }
 }
 
 impl<T: ColorSpace + Copy> Shader<T> for GouraudShader<T> {
-    fn vertex(&mut self, obj_face: ObjFace, light_dir: Vec3) -> [Vec3; 3] {
-        let mut transformed_face = obj_face.vertices.clone();
+    type Varying = GouraudVarying;
+
+    fn vertex(&mut self, obj_face: ObjFace) -> ([Vec4; 3], GouraudVarying) {
+        let mut varying = GouraudVarying {
+            intensity: [Vec3::ZERO; 3],
+            uv: obj_face.texture_vertices,
+            material: obj_face.material
+        };
+        let mut transformed_face = [Vec4::ZERO; 3];
         for i in 0..3 {
             let normal = 
                 self.uniform_transform
-                .ndc_inv_tr_transform(obj_face.normals[i])
+                .view_normal_transform(obj_face.normals[i])
                 .normalize();
-            self.varying_texture_coords[i] = 
-                self.uniform_model
-                .texture_pixel_coords(obj_face.texture_vertices[i].x, obj_face.texture_vertices[i].y)
-                .extend(0.0);
             transformed_face[i] = 
                 self.uniform_transform
-                .ndc_transform(obj_face.vertices[i]);
-            self.varying_intensity[i] = f32::max(0.0, normal.dot(light_dir));
+                .clip_transform(obj_face.vertices[i]);
+
+            // sum up every light at the vertex
+            let position = self.uniform_transform.view_transform(obj_face.vertices[i]);
+            varying.intensity[i] = accumulate_diffuse(&self.uniform_lights, position, normal);
         }
-        transformed_face
+        (transformed_face, varying)
     }
 
-    fn fragment(&self, bary_coords: Vec3, color: &mut T) -> bool {
-        *color = {
-            let interpolated_coords = bary_to_point(&bary_coords, &self.varying_texture_coords);
-            self.uniform_model.get_texture_color(interpolated_coords.x as usize, interpolated_coords.y as usize)
-        }; 
-        let intensity = Vec3::from_array(self.varying_intensity).dot(bary_coords);
-        color.shade(intensity);
+    fn fragment(&self, varying: &GouraudVarying, bary_coords: Vec3, color: &mut T) -> bool {
+        let uv = bary_to_point(&bary_coords, &varying.uv).truncate();
+        *color = self.uniform_model.get_texture_color(varying.material, uv);
+        let intensity = bary_to_point(&bary_coords, &varying.intensity);
+        apply_light(color, Vec3::ZERO, intensity);
+        color.set_alpha(color.alpha() * self.uniform_model.get_alpha(varying.material, uv));
         false
     }
-}
\ No newline at end of file
+}
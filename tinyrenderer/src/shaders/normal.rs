@@ -1,64 +1,71 @@
-use crate::{rasterizer::bary_to_point, ColorSpace, Model, ObjFace, Transform};
+use crate::{light::{accumulate_diffuse, apply_light, Light}, rasterizer::bary_to_point, ColorSpace, Model, ObjFace, Transform};
 use glam::*;
 use super::shader::Shader;
 
 // Shading using normal-mapped tga
 pub struct NormalMappedShader<T: ColorSpace + Copy> {
-    varying_texture_coords: [Vec3; 3],
     uniform_model: Model<T>,
     uniform_transform: Transform,
-    uniform_light_dir: Vec3
+    uniform_lights: Vec<Light> // in view space
 }
 
 impl<'a, T: ColorSpace + Copy> NormalMappedShader<T> {
-    pub fn new(model: Model<T>, transform: Transform) -> Self {
+    pub fn new(model: Model<T>, transform: Transform, lights: &[Light]) -> Self {
         NormalMappedShader {
-            varying_texture_coords: [Vec3::new(0.0, 0.0, 0.0); 3],
             uniform_model: model,
             uniform_transform: transform,
-            uniform_light_dir: Vec3::ZERO // need to store here since i'm using this in fragment fn, instead of vertex fn
+            uniform_lights: lights.iter().map(|light| light.view_transform(&transform)).collect()
         }
     }
 }
 
+pub struct NormalMappedVarying {
+    uv: [Vec3; 3],
+    normals: [Vec3; 3], // used if there's no normal map
+    material: Option<usize>,
+    view_coords: [Vec3; 3] // for lights with a position
+}
+
 impl<T: ColorSpace + Copy> Shader<T> for NormalMappedShader<T> {
-    fn vertex(&mut self, obj_face: ObjFace, light_dir: Vec3) -> [Vec3; 3] {
-        self.uniform_light_dir = light_dir;
-        let mut transformed_face = obj_face.vertices.clone();
+    type Varying = NormalMappedVarying;
+
+    fn vertex(&mut self, obj_face: ObjFace) -> ([Vec4; 3], NormalMappedVarying) {
+        let varying = NormalMappedVarying {
+            uv: obj_face.texture_vertices,
+            normals: obj_face.normals,
+            material: obj_face.material,
+            view_coords: obj_face.vertices.map(|vertex| self.uniform_transform.view_transform(vertex))
+        };
+        let mut transformed_face = [Vec4::ZERO; 3];
         for i in 0..3 {
-            self.varying_texture_coords[i] = 
-                self.uniform_model
-                .texture_pixel_coords(obj_face.texture_vertices[i].x, obj_face.texture_vertices[i].y)
-                .extend(0.0);
-            transformed_face[i] = self.uniform_transform.ndc_transform(obj_face.vertices[i]);
+            transformed_face[i] = self.uniform_transform.clip_transform(obj_face.vertices[i]);
         }
-        transformed_face
+        (transformed_face, varying)
     }
 
-    fn fragment(&self, bary_coords: Vec3, color: &mut T) -> bool {
-        // compute actual pixel coordinate for texture + normal image
-        let interpolated_coords = bary_to_point(&bary_coords, &self.varying_texture_coords);
+    fn fragment(&self, varying: &NormalMappedVarying, bary_coords: Vec3, color: &mut T) -> bool {
+        // compute uv coordinates for texture + normal image
+        let uv = bary_to_point(&bary_coords, &varying.uv).truncate();
         
         // get the normal vec at this pixel
         let normal = {
             let untransformed_normal = self.uniform_model
-                .get_normal(interpolated_coords.x as usize, interpolated_coords.y as usize);
+                .get_normal(varying.material, uv)
+                .unwrap_or_else(|| bary_to_point(&bary_coords, &varying.normals));
 
             self.uniform_transform
-                .ndc_inv_tr_transform(untransformed_normal)
+                .view_normal_transform(untransformed_normal)
                 .normalize()
         };
         //println!("{bary_coords:?}   ->   {normal}");
 
-        // transform light vector into ndc
-        let light = self.uniform_transform
-            .ndc_transform(self.uniform_light_dir)
-            .normalize();
+        // sum up every light (already in view space)
+        let position = bary_to_point(&bary_coords, &varying.view_coords);
+        let intensity = accumulate_diffuse(&self.uniform_lights, position, normal);
 
         // shade the color
-        let intensity = f32::max(0.0, normal.dot(light));
-        //*color = self.uniform_model.get_texture_color(interpolated_coords.x as usize, interpolated_coords.y as usize);
-        color.shade(intensity);
+        //*color = self.uniform_model.get_texture_color(varying.material, uv);
+        apply_light(color, Vec3::ZERO, intensity);
         false
     }
 }
@@ -1,75 +1,94 @@
-use crate::{rasterizer::bary_to_point, ColorSpace, Model, ObjFace, Transform};
+use crate::{light::{accumulate_light, apply_light, Light, Occlusion}, rasterizer::bary_to_point, ColorSpace, Model, ObjFace, Transform};
 use glam::*;
-use super::shader::Shader;
+use super::shader::{OccludedShader, Shader};
 
 // Shading using normal-mapped tga + specular lighting
 pub struct NormalSpecularShader<T: ColorSpace + Copy> {
-    varying_texture_coords: [Vec3; 3],
     uniform_model: Model<T>,
     uniform_transform: Transform,
-    uniform_light_dir: Vec3
+    uniform_occlusion: Occlusion, // point lights' shadows + SSAO
+    uniform_lights: Vec<Light> // in view space
 }
 
 impl<T: ColorSpace + Copy> NormalSpecularShader<T> {
-    pub fn new(model: Model<T>, transform: Transform) -> Self {
+    pub fn new(model: Model<T>, transform: Transform, lights: &[Light]) -> Self {
         NormalSpecularShader {
-            varying_texture_coords: [Vec3::new(0.0, 0.0, 0.0); 3],
             uniform_model: model,
             uniform_transform: transform,
-            uniform_light_dir: Vec3::ZERO // need to store here since i'm using this in fragment fn, instead of vertex fn
+            uniform_occlusion: Occlusion::new(transform.get_whole_transform()),
+            uniform_lights: lights.iter().map(|light| light.view_transform(&transform)).collect()
         }
     }
 }
 
+impl<T: ColorSpace + Copy> OccludedShader for NormalSpecularShader<T> {
+    fn occlusion_mut(&mut self) -> &mut Occlusion {
+        &mut self.uniform_occlusion
+    }
+}
+
+pub struct NormalSpecularVarying {
+    uv: [Vec3; 3],
+    normals: [Vec3; 3], // used if there's no normal map
+    material: Option<usize>,
+    coords: [Vec3; 3]
+}
+
 impl<T: ColorSpace + Copy> Shader<T> for NormalSpecularShader<T> {
-    fn vertex(&mut self, obj_face: ObjFace, light_dir: Vec3) -> [Vec3; 3] {
-        self.uniform_light_dir = light_dir; 
-        let mut transformed_face = obj_face.vertices.clone();
+    type Varying = NormalSpecularVarying;
+
+    fn vertex(&mut self, obj_face: ObjFace) -> ([Vec4; 3], NormalSpecularVarying) {
+        let varying = NormalSpecularVarying {
+            uv: obj_face.texture_vertices,
+            normals: obj_face.normals,
+            material: obj_face.material,
+            coords: obj_face.vertices
+        };
+        let mut transformed_face = [Vec4::ZERO; 3];
         for i in 0..3 {
-            self.varying_texture_coords[i] = 
-                self.uniform_model
-                .texture_pixel_coords(obj_face.texture_vertices[i].x, obj_face.texture_vertices[i].y)
-                .extend(0.0);
             transformed_face[i] = 
                 self.uniform_transform
-                .ndc_transform(obj_face.vertices[i]);
+                .clip_transform(obj_face.vertices[i]);
         }
-        transformed_face
+        (transformed_face, varying)
     }
 
-    fn fragment(&self, bary_coords: Vec3, color: &mut T) -> bool {
+    fn fragment(&self, varying: &NormalSpecularVarying, bary_coords: Vec3, color: &mut T) -> bool {
         // compute actual coords for corresponding pixel in texture + specular + normal images 
-        let interpolated_coords = bary_to_point(&bary_coords, &self.varying_texture_coords);
+        let uv = bary_to_point(&bary_coords, &varying.uv).truncate();
 
-        // get normal of corresponding pixel
-        let untransformed_normal = self.uniform_model.get_normal(interpolated_coords.x as usize, interpolated_coords.y as usize);
+        // get normal of corresponding pixel (or the interpolated vertex normal, without a normal map)
+        let untransformed_normal = self.uniform_model
+            .get_normal(varying.material, uv)
+            .unwrap_or_else(|| bary_to_point(&bary_coords, &varying.normals));
         let normal = self.uniform_transform
-                        .ndc_inv_tr_transform( untransformed_normal)
+                        .view_normal_transform(untransformed_normal)
                         .normalize();
         
-        // get transformed light vec
-        let light = self.uniform_transform.ndc_transform(self.uniform_light_dir).normalize();
-
-        // diffuse light - normal lighting
-        let diffuse_light = normal.dot(light).max(0.0);
-
-        // specular light - "highlight" from reflection of light
-        let reflection = (normal * (2.0 * normal.dot(light)) - light).normalize();
-        let specular_light = {
-            let specularity = self.uniform_model.get_specularity(interpolated_coords.x as usize, interpolated_coords.y as usize);
-            (reflection.z.max(0.0)).powf(specularity) // extremely bright at centre, then quickly disappears outwards
-        };      
-        
-        // use weighted ambient + diffuse + specular light to modify each color
-        let ambient_w = 5.0;
+        // sum up every light's diffuse light (normal lighting) + specular light ("highlight" from reflection of light), weighted
         let diffuse_w = 1.0;
-        let spec_w = 0.6;
-        *color = self.uniform_model.get_texture_color(interpolated_coords.x as usize, interpolated_coords.y as usize);
-        let mut color_vec = color.to_vec();
-        for c in &mut color_vec {
-            *c = f32::min(ambient_w + (*c as f32)*(diffuse_w*diffuse_light + spec_w*specular_light), 255.0) as u8;
-        }
-        color.from_vec(color_vec).unwrap();
+        let spec_w = self.uniform_model.get_specular_weight(varying.material);
+        let specularity = self.uniform_model.get_specularity(varying.material, uv);
+        let object_point = bary_to_point(&bary_coords, &varying.coords);
+        let surface_normal = bary_to_point(&bary_coords, &varying.normals).normalize_or_zero(); // (for shadows)
+        let position = self.uniform_transform.view_transform(object_point);
+        let light = accumulate_light(
+            &self.uniform_lights,
+            position,
+            |i| self.uniform_occlusion.shadows.visibility(i, object_point, surface_normal),
+            |light_dir| {
+                let diffuse_light = normal.dot(light_dir).max(0.0);
+                let reflection = (normal * (2.0 * normal.dot(light_dir)) - light_dir).normalize();
+                let specular_light = (reflection.z.max(0.0)).powf(specularity); // extremely bright at centre, then quickly disappears outwards
+                Vec3::splat(diffuse_w*diffuse_light + spec_w*specular_light)
+            }
+        );
+        
+        // then use it + weighted ambient light to modify each color
+        let ambient_w = 5.0 * self.uniform_model.get_occlusion(varying.material, uv) * self.uniform_occlusion.ambient_occlusion(object_point);
+        *color = self.uniform_model.get_texture_color(varying.material, uv);
+        apply_light(color, self.uniform_model.get_ambient(varying.material) * ambient_w, light);
+        color.set_alpha(color.alpha() * self.uniform_model.get_alpha(varying.material, uv));
         false
     }
 }
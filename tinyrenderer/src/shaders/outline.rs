@@ -0,0 +1,50 @@
+use crate::{ColorSpace, ObjFace, Transform, RGBA};
+use glam::*;
+use super::shader::Shader;
+
+// Flat colored silhouette, pushed outwards along the vertex normals by a fixed number of pixels.
+// Drawn where the stencil doesn't have the object itself, only the rim around it is left (ie an outline).
+pub struct OutlineShader {
+    uniform_transform: Transform,
+    uniform_color: RGBA,
+    uniform_width: f32 // in pixels
+}
+
+impl OutlineShader {
+    pub fn new(transform: Transform, color: RGBA, width: f32) -> Self {
+        OutlineShader {
+            uniform_transform: transform,
+            uniform_color: color,
+            uniform_width: width
+        }
+    }
+}
+
+impl<T: ColorSpace + Copy> Shader<T> for OutlineShader {
+    type Varying = ();
+
+    fn vertex(&mut self, obj_face: ObjFace) -> ([Vec4; 3], ()) {
+        // how far a pixel is in ndc (the viewport scales [-1, 1] by half its size)
+        let pixel = Vec2::new(1.0 / self.uniform_transform.viewport.x_axis.x, 1.0 / self.uniform_transform.viewport.y_axis.y);
+
+        let transformed_face = std::array::from_fn(|i| {
+            let clip = self.uniform_transform.clip_transform(obj_face.vertices[i]);
+
+            // the normal's direction on screen (normals are directions, so w = 0)
+            let normal = self.uniform_transform.view_normal_transform(obj_face.normals[i]);
+            let screen_normal = (self.uniform_transform.projection * normal.extend(0.0))
+                .xy()
+                .normalize_or_zero();
+
+            // offset in ndc, then undo the divide so the width doesn't shrink with distance
+            let offset = screen_normal * pixel * self.uniform_width * clip.w;
+            clip + offset.extend(0.0).extend(0.0)
+        });
+        (transformed_face, ())
+    }
+
+    fn fragment(&self, _varying: &(), _bary_coords: Vec3, color: &mut T) -> bool {
+        *color = T::from_rgba(self.uniform_color);
+        false
+    }
+}
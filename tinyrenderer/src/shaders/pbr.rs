@@ -0,0 +1,201 @@
+use crate::{light::{accumulate_light, Light, Occlusion}, rasterizer::bary_to_point, ColorSpace, Model, ObjFace, OccludedShader, Shader, Transform};
+use glam::*;
+use std::f32::consts::PI;
+
+/// Physically based shading, with glTF style metallic-roughness materials:
+/// Cook-Torrance specular (GGX distribution, Smith geometry, Schlick fresnel) over a Lambertian diffuse.
+///
+/// Everything is lit in linear floats, ie textures (+ emission) are decoded from sRGB first,
+/// and the result only gets encoded (+ clamped) into the output colorspace at the very end.
+/// Light intensities are radiance, so a white surface facing a white light of intensity 1 reflects 1/pi of it.
+pub struct PbrShader<T: ColorSpace + Copy> {
+    uniform_model: Model<T>,
+    uniform_transform: Transform,
+    uniform_lights: Vec<Light>, // in view space
+    uniform_ambient: Vec3, // ambient light, in linear color
+    uniform_occlusion: Occlusion // point lights' shadows + SSAO
+}
+
+impl<T: ColorSpace + Copy> PbrShader<T> {
+    pub fn new(model: Model<T>, transform: Transform, lights: &[Light]) -> Self {
+        PbrShader {
+            uniform_model: model,
+            uniform_transform: transform,
+            uniform_lights: lights.iter().map(|light| light.view_transform(&transform)).collect(),
+            uniform_ambient: Vec3::splat(0.03),
+            uniform_occlusion: Occlusion::new(transform.get_whole_transform())
+        }
+    }
+
+    pub fn set_ambient(&mut self, ambient: Vec3) {
+        self.uniform_ambient = ambient;
+    }
+}
+
+impl<T: ColorSpace + Copy> OccludedShader for PbrShader<T> {
+    fn occlusion_mut(&mut self) -> &mut Occlusion {
+        &mut self.uniform_occlusion
+    }
+}
+
+pub struct PbrVarying {
+    material: Option<usize>,
+    uv: [Vec3; 3],
+    normals: [Vec3; 3], // in view space
+    view_coords: [Vec3; 3],
+    coords: [Vec3; 3],
+    object_normals: [Vec3; 3], // for shadows
+    tangent: Vec3, // the face's, in view space (zero if its uvs are degenerate)
+    bitangent: Vec3
+}
+
+impl<T: ColorSpace + Copy> Shader<T> for PbrShader<T> {
+    type Varying = PbrVarying;
+
+    fn vertex(&mut self, obj_face: ObjFace) -> ([Vec4; 3], PbrVarying) {
+        // the face's tangent + bitangent, ie which ways u and v increase along it
+        let (edge1, edge2) = (obj_face.vertices[1] - obj_face.vertices[0], obj_face.vertices[2] - obj_face.vertices[0]);
+        let (duv1, duv2) = (obj_face.texture_vertices[1] - obj_face.texture_vertices[0], obj_face.texture_vertices[2] - obj_face.texture_vertices[0]);
+        let det = duv1.x * duv2.y - duv2.x * duv1.y;
+        let (tangent, bitangent) = if det.abs() > f32::EPSILON {
+            ((edge1 * duv2.y - edge2 * duv1.y) / det, (edge2 * duv1.x - edge1 * duv2.x) / det)
+        } else {
+            (Vec3::ZERO, Vec3::ZERO)
+        };
+
+        let varying = PbrVarying {
+            material: obj_face.material,
+            uv: obj_face.texture_vertices,
+            normals: obj_face.normals.map(|n| self.uniform_transform.view_normal_transform(n)),
+            view_coords: obj_face.vertices.map(|v| self.uniform_transform.view_transform(v)),
+            coords: obj_face.vertices,
+            object_normals: obj_face.normals,
+            tangent: self.uniform_transform.view_dir_transform(tangent),
+            bitangent: self.uniform_transform.view_dir_transform(bitangent)
+        };
+        let transformed_face = obj_face.vertices.map(|v| self.uniform_transform.clip_transform(v));
+        (transformed_face, varying)
+    }
+
+    fn fragment(&self, varying: &PbrVarying, bary_coords: Vec3, color: &mut T) -> bool {
+        let model = &self.uniform_model;
+        let uv = bary_to_point(&bary_coords, &varying.uv).truncate();
+
+        // the material, in linear color
+        let base_color = model.get_texture_color(varying.material, uv);
+        let albedo = to_linear(&base_color);
+        let metallic = model.get_metallic(varying.material, uv).clamp(0.0, 1.0);
+        let roughness = model.get_roughness(varying.material, uv).clamp(0.04, 1.0); // (perfectly smooth surfaces have infinitely sharp highlights)
+        let emissive = Vec3::from_array(model.get_emissive(varying.material, uv).to_array().map(srgb_to_linear));
+
+        // the normal: from the tangent space normal map, else the object space one, else the interpolated vertex normal
+        let interpolated_normal = bary_to_point(&bary_coords, &varying.normals).normalize();
+        let tangent = (varying.tangent - interpolated_normal * interpolated_normal.dot(varying.tangent)).normalize_or_zero();
+        let normal = match model.get_tangent_normal(varying.material, uv) {
+            Some(tangent_normal) if tangent != Vec3::ZERO => {
+                // (mirrored uvs flip the bitangent)
+                let handedness = interpolated_normal.cross(tangent).dot(varying.bitangent).signum();
+                let bitangent = interpolated_normal.cross(tangent) * handedness;
+                Mat3::from_cols(tangent, bitangent, interpolated_normal) * tangent_normal
+            },
+            _ => model
+                .get_normal(varying.material, uv)
+                .map_or(interpolated_normal, |normal| self.uniform_transform.view_normal_transform(normal))
+        }
+        .normalize();
+
+        // the view direction (the camera's at the origin, in view space)
+        let position = bary_to_point(&bary_coords, &varying.view_coords);
+        let view_dir = (-position).normalize();
+        let n_dot_v = normal.dot(view_dir).max(1e-4);
+
+        // how much light is reflected head on: 4% for dielectrics, the base color for metals
+        let f0 = Vec3::splat(0.04).lerp(albedo, metallic);
+        let alpha = roughness * roughness;
+        let k = (roughness + 1.0) * (roughness + 1.0) / 8.0;
+
+        // sum up every light's reflected radiance
+        let object_point = bary_to_point(&bary_coords, &varying.coords);
+        let surface_normal = bary_to_point(&bary_coords, &varying.object_normals).normalize_or_zero();
+        let reflected = accumulate_light(
+            &self.uniform_lights,
+            position,
+            |i| self.uniform_occlusion.shadows.visibility(i, object_point, surface_normal),
+            |light_dir| {
+                let n_dot_l = normal.dot(light_dir);
+                if n_dot_l <= 0.0 {
+                    return Vec3::ZERO;
+                }
+                let half = (view_dir + light_dir).normalize();
+
+                let distribution = ggx_distribution(normal.dot(half).max(0.0), alpha);
+                let geometry = smith_geometry(n_dot_v, k) * smith_geometry(n_dot_l, k);
+                let fresnel = schlick_fresnel(half.dot(view_dir).max(0.0), f0);
+                let specular = fresnel * (distribution * geometry / (4.0 * n_dot_v * n_dot_l));
+
+                // whatever isn't reflected is diffused, except by metals, which absorb it
+                let diffuse = (Vec3::ONE - fresnel) * (1.0 - metallic) * albedo / PI;
+                (diffuse + specular) * n_dot_l
+            }
+        );
+
+        // then ambient light (occluded) + emission
+        let occlusion = model.get_occlusion(varying.material, uv) * self.uniform_occlusion.ambient_occlusion(object_point);
+        let ambient = self.uniform_ambient * model.get_ambient(varying.material) * albedo * occlusion;
+
+        *color = base_color;
+        from_linear(color, reflected + ambient + emissive);
+        color.set_alpha(color.alpha() * model.get_alpha(varying.material, uv));
+        false
+    }
+}
+
+// Trowbridge-Reitz GGX: how many microfacets face along the half vector
+fn ggx_distribution(n_dot_h: f32, alpha: f32) -> f32 {
+    let alpha2 = alpha * alpha;
+    let denom = n_dot_h * n_dot_h * (alpha2 - 1.0) + 1.0;
+    alpha2 / (PI * denom * denom)
+}
+
+// Schlick-GGX: how many microfacets aren't shadowed (or masked) along one direction
+fn smith_geometry(n_dot_x: f32, k: f32) -> f32 {
+    n_dot_x / (n_dot_x * (1.0 - k) + k)
+}
+
+// how much light gets reflected, rather than refracted, at the given angle
+fn schlick_fresnel(cos: f32, f0: Vec3) -> Vec3 {
+    f0 + (Vec3::ONE - f0) * (1.0 - cos).powi(5)
+}
+
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+}
+
+fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 { c * 12.92 } else { 1.055 * c.powf(1.0 / 2.4) - 0.055 }
+}
+
+// a color's r, g + b, decoded into linear floats (grayscale goes to all three)
+fn to_linear<T: ColorSpace>(color: &T) -> Vec3 {
+    let channels = color.to_vec();
+    let channel = |i: usize| srgb_to_linear(channels[i.min(channels.len() - 1)] as f32 / 255.0);
+    if channels.len() < 3 {
+        Vec3::splat(channel(0))
+    } else {
+        Vec3::new(channel(0), channel(1), channel(2))
+    }
+}
+
+// sets a color's r, g + b from linear floats (grayscale gets their mean); alpha isn't touched
+fn from_linear<T: ColorSpace>(color: &mut T, linear: Vec3) {
+    let encode = |c: f32| (linear_to_srgb(c.clamp(0.0, 1.0)) * 255.0).round() as u8;
+    let mut channels = color.to_vec();
+    if channels.len() < 3 {
+        channels[0] = encode((linear.x + linear.y + linear.z) / 3.0);
+    } else {
+        for (c, linear) in channels.iter_mut().zip(linear.to_array()) {
+            *c = encode(linear);
+        }
+    }
+    color.from_vec(channels).unwrap();
+}
@@ -1,11 +1,58 @@
-use crate::{ColorSpace, ObjFace};
+use crate::{deferred::Surface, light::Occlusion, shadows::CubeShadowMap, ColorSpace, Grayscale, Image, ObjFace};
 use glam::*;
 
 pub trait Shader<T: ColorSpace + Copy> {
 
-    /// Transforms raw coordinates into normalized device coordinates + prepares necessary data for fragment shader
-    fn vertex(&mut self, obj_face: ObjFace, light_dir: Vec3) -> [Vec3; 3];
+    /// Whatever the vertex shader passes on to the fragment shader for a triangle (ie its varyings).
+    /// It's kept apart from the shader itself, so that triangles can be shaded later on (eg in parallel, by tile).
+    type Varying;
+
+    /// Transforms raw coordinates into clip coordinates + prepares necessary data for fragment shader
+    /// (the homogeneous divide is left to the pipeline). Lights, like everything else the shader needs, are uniforms.
+    fn vertex(&mut self, obj_face: ObjFace) -> ([Vec4; 3], Self::Varying);
 
     /// Modifies color of a pixel at the barymetric coordinates + returns whether to render it
-    fn fragment(&self, bary_coords: Vec3, color: &mut T) -> bool;
-}
\ No newline at end of file
+    fn fragment(&self, varying: &Self::Varying, bary_coords: Vec3, color: &mut T) -> bool;
+
+    /// Like `fragment`, but with a color per target of the framebuffer being rendered into.
+    /// By default, every target gets the same color (like gl_FragColor).
+    fn fragment_targets(&self, varying: &Self::Varying, bary_coords: Vec3, colors: &mut [T]) -> bool {
+        let mut color = colors.first().copied().unwrap_or(T::white());
+        let discard = self.fragment(varying, bary_coords, &mut color);
+        colors.fill(color);
+        discard
+    }
+}
+
+/// A shader for the geometry pass of deferred shading (see `deferred::GBuffer`):
+/// rather than lighting a fragment, it only works out the surface there, for a `LightingShader` to light later on.
+pub trait GeometryShader<T: ColorSpace + Copy> {
+    type Varying;
+
+    /// Same as `Shader::vertex`.
+    fn vertex(&mut self, obj_face: ObjFace) -> ([Vec4; 3], Self::Varying);
+
+    /// The surface at the barymetric coordinates, or None to discard the fragment
+    fn fragment(&self, varying: &Self::Varying, bary_coords: Vec3) -> Option<Surface<T>>;
+}
+
+/// A shader for the lighting pass of deferred shading, which runs once per pixel of the G-buffer.
+pub trait LightingShader<T: ColorSpace + Copy> {
+    /// Modifies color of a pixel, given the (closest) surface there
+    fn shade(&self, surface: &Surface<T>, color: &mut T);
+}
+
+/// A shader whose lights can be shadowed + ambient light occluded (see `light::Occlusion`).
+pub trait OccludedShader {
+    fn occlusion_mut(&mut self) -> &mut Occlusion;
+
+    /// Scales the ambient term by `occlusion`, per pixel (eg from SSAO).
+    fn set_occlusion(&mut self, occlusion: Image<Grayscale>) {
+        self.occlusion_mut().ambient = Some(occlusion);
+    }
+
+    /// Makes `lights[light]` cast shadows with `shadow_map` (rendered from its position, in object space).
+    fn add_cube_shadow_map(&mut self, light: usize, shadow_map: CubeShadowMap) {
+        self.occlusion_mut().shadows.add_cube_map(light, shadow_map);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{rasterizer::bary_to_point, ColorSpace, Model, ObjFace, Shader, Transform};
+use crate::{framebuffer::DepthBuffer, light::{accumulate_light, apply_light, Light, Occlusion}, rasterizer::bary_to_point, shadows::{CascadedShadowMap, ShadowSettings}, ColorSpace, Model, ObjFace, OccludedShader, Shader, Transform};
 use glam::*;
 
 /// Shadow shader is composed of 2 shaders: depth shader + actual shader.
@@ -8,132 +8,173 @@ use glam::*;
 
 // Calculates visibility information by placing camera at light source.
 pub struct DepthShader {
-    varying_tri: [Vec3; 3],
-    uniform_light_source: Vec3,
-    uniform_depth_transform: Transform //note: model_view must be from light_dir perspective
+    uniform_depth_transform: Transform //note: model_view must be from the light's perspective
 }
 
 impl DepthShader {
-    pub fn new(light_source: Vec3, depth_transform: Transform) -> Self {
+    pub fn new(depth_transform: Transform) -> Self {
         DepthShader {
-            varying_tri: [Vec3::ZERO; 3],
-            uniform_light_source: light_source,
             uniform_depth_transform: depth_transform
         }
     }
 }
 
+pub struct DepthVarying {
+    tri: [Vec4; 3] // clip coords, since we can only divide by w after interpolating
+}
+
 impl<T: ColorSpace + Copy> Shader<T> for DepthShader {
-    fn vertex(&mut self, obj_face: ObjFace, light_dir: Vec3) -> [Vec3; 3] {
-        self.uniform_light_source = light_dir;
-        for i in 0..3 { 
-            self.varying_tri[i] = self.uniform_depth_transform
-                .ndc_transform(obj_face.vertices[i]);
-        }
-        self.varying_tri
+    type Varying = DepthVarying;
+
+    fn vertex(&mut self, obj_face: ObjFace) -> ([Vec4; 3], DepthVarying) {
+        let transformed_face = obj_face.vertices.map(|v| self.uniform_depth_transform.clip_transform(v));
+        (transformed_face, DepthVarying { tri: transformed_face })
     }
 
-    fn fragment(&self, bary_coords: Vec3, color: &mut T) -> bool {
-        let coords = bary_to_point(&bary_coords, &self.varying_tri);
+    fn fragment(&self, varying: &DepthVarying, bary_coords: Vec3, color: &mut T) -> bool {
+        let coords = varying.tri[0] * bary_coords.x
+            + varying.tri[1] * bary_coords.y
+            + varying.tri[2] * bary_coords.z;
+        let coords = coords.xyz() / coords.w;
         *color = T::white();
-        color.shade((coords.z+1.0)/2.0); //extrapolate to [0, 1] then shade by z-value
+        color.shade((1.0-coords.z)/2.0); //extrapolate to [0, 1] then shade by z-value (near plane is -1, so flip it)
         false
     }
 }
 
 
 // Actual shader (normal + specular mapping), but uses shadowbuffer to locate z-values to shade as shadows
+// (the shadow map is the first light's; others only cast shadows if they're given a cube shadow map)
 pub struct ShadowShader<T: ColorSpace + Copy> {
-    varying_texture_coords: [Vec3; 3],
-    varying_screen_coords: [Vec3; 3],
     uniform_model: Model<T>,
     uniform_transform: Transform,
-    uniform_shadow_transform: Affine3A, // transforms screen coords of current fragment into shadow screen coords
-    uniform_shadowbuffer: Vec<f32>, // buffer from depth shader
-    uniform_light_dir: Vec3
+    uniform_shadow_transform: Mat4, // transforms coords of current fragment into shadow screen coords (ie the depth shader's whole transform)
+    uniform_shadow_map: DepthBuffer, // depth buffer from depth shader
+    uniform_occlusion: Occlusion, // the shadow settings, point lights' shadows + SSAO
+    uniform_texel_size: f32, // a shadow map texel's size, in object space
+    uniform_depth_per_texel: f32, // how much shadow map depth a texel's worth of distance (towards the light) is
+    uniform_lights: Vec<Light>, // in view space
+    uniform_shadow_light: Option<Light>, // the first light, in object space (for shadow bias)
+    uniform_cascades: Option<CascadedShadowMap>, // replaces the shadow map, if set
+    uniform_debug_cascades: bool // tints each cascade its own color
 }
 
 impl<T: ColorSpace + Copy> ShadowShader<T> {
-    pub fn new(model: Model<T>, transform: Transform, shadow_transform: Affine3A, shadowbuffer: Vec<f32>) -> Self {
+    pub fn new(model: Model<T>, transform: Transform, shadow_transform: Mat4, shadow_map: DepthBuffer, lights: &[Light]) -> Self {
+        // (the rows of the shadow transform scale object space into texels + depth)
+        let texel_size = 1.0 / shadow_transform.row(0).truncate().length();
         ShadowShader {
-            varying_texture_coords: [Vec3::ZERO; 3],
-            varying_screen_coords: [Vec3::ZERO; 3],
             uniform_model: model,
             uniform_transform: transform,
             uniform_shadow_transform: shadow_transform,
-            uniform_shadowbuffer: shadowbuffer,
-            uniform_light_dir: Vec3::ZERO
+            uniform_shadow_map: shadow_map,
+            uniform_occlusion: Occlusion::new(transform.get_whole_transform()),
+            uniform_texel_size: texel_size,
+            uniform_depth_per_texel: shadow_transform.row(2).truncate().length() * texel_size,
+            uniform_lights: lights.iter().map(|light| light.view_transform(&transform)).collect(),
+            uniform_shadow_light: lights.first().copied(),
+            uniform_cascades: None,
+            uniform_debug_cascades: false
         }
     }
+
+    pub fn set_shadow_settings(&mut self, settings: ShadowSettings) {
+        self.uniform_occlusion.shadows.settings = settings;
+    }
+
+    /// Shadows the first light with `cascades` (rendered from its direction, in object space) instead of the shadow map.
+    pub fn set_cascaded_shadow_map(&mut self, cascades: CascadedShadowMap) {
+        self.uniform_cascades = Some(cascades);
+    }
+
+    /// Tints everything by which cascade it's in (see `CASCADE_COLORS`).
+    pub fn set_debug_cascades(&mut self, debug: bool) {
+        self.uniform_debug_cascades = debug;
+    }
 }
 
-impl<T: ColorSpace + Copy> Shader<T> for ShadowShader<T> {
-    fn vertex(&mut self, obj_face: ObjFace, light_dir: Vec3) -> [Vec3; 3] {
-        self.uniform_light_dir = light_dir; 
-        let mut transformed_face = obj_face.vertices.clone();
-        for i in 0..3 {
-            self.varying_texture_coords[i] = 
-                self.uniform_model
-                .texture_pixel_coords(obj_face.texture_vertices[i].x, obj_face.texture_vertices[i].y)
-                .extend(0.0);
-            transformed_face[i] = 
-                self.uniform_transform
-                .ndc_transform(obj_face.vertices[i]);
-            self.varying_screen_coords[i] = 
-                self.uniform_transform
-                .get_whole_transform()
-                .transform_point3(obj_face.vertices[i])
-        }
-        transformed_face
+impl<T: ColorSpace + Copy> OccludedShader for ShadowShader<T> {
+    fn occlusion_mut(&mut self) -> &mut Occlusion {
+        &mut self.uniform_occlusion
     }
+}
 
-    fn fragment(&self, bary_coords: Vec3, color: &mut T) -> bool {
-        // compute corresponding point in shadow buffer
-        let sb_coords = 
-            self.uniform_shadow_transform.transform_point3(
-                bary_to_point(&bary_coords, &self.varying_screen_coords)
-            );
-        
-        // compute index in shadow buffer (x + y*width)
-        // NOTE: using normal_image width is a hack!!! it should be depth_image's width, but that's not accessible currently
-        let sb_idx = (sb_coords.x + sb_coords.y*self.uniform_model.normal_image.width as f32) as usize;   
+pub struct ShadowVarying {
+    uv: [Vec3; 3],
+    normals: [Vec3; 3], // used if there's no normal map
+    material: Option<usize>,
+    coords: [Vec3; 3]
+}
 
-        // if current point z-value is less than shadowbuffer z-value, reduce brightness (ie create shadow)
-        let magic_value = 43.34; 
-        let shadow = 0.3 + 0.7 * f32::from(self.uniform_shadowbuffer[sb_idx] < (sb_coords.z+magic_value));
+impl<T: ColorSpace + Copy> Shader<T> for ShadowShader<T> {
+    type Varying = ShadowVarying;
+
+    fn vertex(&mut self, obj_face: ObjFace) -> ([Vec4; 3], ShadowVarying) {
+        let varying = ShadowVarying {
+            uv: obj_face.texture_vertices,
+            normals: obj_face.normals,
+            material: obj_face.material,
+            coords: obj_face.vertices
+        };
+        let transformed_face = obj_face.vertices.map(|v| self.uniform_transform.clip_transform(v));
+        (transformed_face, varying)
+    }
+
+    fn fragment(&self, varying: &ShadowVarying, bary_coords: Vec3, color: &mut T) -> bool {
+        // compute corresponding point in shadow buffer, pushed out along the (geometric) normal against acne
+        let settings = &self.uniform_occlusion.shadows.settings;
+        let surface_normal = bary_to_point(&bary_coords, &varying.normals).normalize_or_zero();
+        let object_point = bary_to_point(&bary_coords, &varying.coords);
+        let shadow = match &self.uniform_cascades {
+            Some(cascades) => settings.shade(cascades.visibility(settings, object_point, surface_normal)),
+            None => {
+                let light_dir = self.uniform_shadow_light.map_or(Vec3::ZERO, |light| light.incident(object_point).0);
+                let point = settings.offset_point(object_point, surface_normal, light_dir, self.uniform_texel_size);
+                let sb_coords = self.uniform_shadow_transform.project_point3(point);
+
+                // compare against the shadow map's depth there (filtered, so shadows get soft edges), then reduce brightness by how shadowed it is
+                let bias = settings.depth_bias(surface_normal, light_dir, self.uniform_depth_per_texel);
+                settings.shade(settings.visibility(&self.uniform_shadow_map, sb_coords, bias))
+            }
+        };
 
         // compute actual coords for corresponding pixel in texture + specular + normal images 
-        let interpolated_coords = bary_to_point(&bary_coords, &self.varying_texture_coords);
+        let uv = bary_to_point(&bary_coords, &varying.uv).truncate();
 
-        // get normal of corresponding pixel
-        let untransformed_normal = self.uniform_model.get_normal(interpolated_coords.x as usize, interpolated_coords.y as usize);
+        // get normal of corresponding pixel (or the interpolated vertex normal, without a normal map)
+        let untransformed_normal = self.uniform_model
+            .get_normal(varying.material, uv)
+            .unwrap_or_else(|| bary_to_point(&bary_coords, &varying.normals));
         let normal = self.uniform_transform
-            .ndc_inv_tr_transform( untransformed_normal)
+            .view_normal_transform(untransformed_normal)
             .normalize();
         
-        // get transformed light vec
-        let light = self.uniform_transform.ndc_transform(self.uniform_light_dir).normalize();
-
-        // diffuse light - normal lighting
-        let diffuse_light = normal.dot(light).max(0.0);
-
-        // specular light - "highlight" from reflection of light
-        let reflection = (normal * (2.0 * normal.dot(light)) - light).normalize();
-        let specular_light = {
-            let specularity = self.uniform_model.get_specularity(interpolated_coords.x as usize, interpolated_coords.y as usize);
-            (reflection.z.max(0.0)).powf(specularity) // extremely bright at centre, then quickly disappears outwards
-        };      
-        
-        // use weighted ambient + diffuse + specular light to modify each color
-        let ambient_w = 5.0;
+        // sum up every light's diffuse light (normal lighting) + specular light ("highlight" from reflection of light), weighted
         let diffuse_w = 1.0;
         let spec_w = 0.0;
-        *color = self.uniform_model.get_texture_color(interpolated_coords.x as usize, interpolated_coords.y as usize);
-        let mut color_vec = color.to_vec();
-        for c in &mut color_vec {
-            *c = f32::min(ambient_w + (*c as f32) * shadow * (diffuse_w*diffuse_light + spec_w*specular_light), 255.0) as u8;
+        let specularity = self.uniform_model.get_specularity(varying.material, uv);
+        let position = self.uniform_transform.view_transform(object_point);
+        // how much of each light gets here: light 0 is the one with the shadow map (or cascades), so it gets that shadow,
+        // then any light can have a cube shadow map on top
+        let light_shadow = |i: usize| {
+            let shadow = if i == 0 { shadow } else { 1.0 };
+            shadow * self.uniform_occlusion.shadows.visibility(i, object_point, surface_normal)
+        };
+        let light = accumulate_light(&self.uniform_lights, position, light_shadow, |light_dir| {
+            let diffuse_light = normal.dot(light_dir).max(0.0);
+            let reflection = (normal * (2.0 * normal.dot(light_dir)) - light_dir).normalize();
+            let specular_light = (reflection.z.max(0.0)).powf(specularity); // extremely bright at centre, then quickly disappears outwards
+            Vec3::splat(diffuse_w*diffuse_light + spec_w*specular_light)
+        });
+        
+        // then use it + weighted ambient light to modify each color
+        let ambient_w = 5.0 * self.uniform_model.get_occlusion(varying.material, uv) * self.uniform_occlusion.ambient_occlusion(object_point);
+        *color = self.uniform_model.get_texture_color(varying.material, uv);
+        apply_light(color, self.uniform_model.get_ambient(varying.material) * ambient_w, light);
+        if let (true, Some(cascades)) = (self.uniform_debug_cascades, &self.uniform_cascades) {
+            apply_light(color, Vec3::ZERO, cascades.debug_color(object_point));
         }
-        color.from_vec(color_vec).unwrap();
+        color.set_alpha(color.alpha() * self.uniform_model.get_alpha(varying.material, uv));
         false
     }
 }
@@ -1,100 +1,107 @@
-use crate::{rasterizer::bary_to_point, ColorSpace, Model, ObjFace, Shader, Transform};
+use crate::{light::{accumulate_diffuse, apply_light, Light}, rasterizer::bary_to_point, ColorSpace, Model, ObjFace, Shader, Transform};
 use glam::*;
 
 // Shading using normal-mapped Darboux frame tga + specular lighting
 pub struct TangentNormalShader<T: ColorSpace + Copy> {
-    varying_texture_coords: [Vec3; 3],
-    varying_normals: [Vec3; 3], // need to interpolate normal for darboux transform computation
-    varying_ndc: [Vec3; 3],
-    varying_uv: [Vec3; 3],
     uniform_model: Model<T>,
     uniform_transform: Transform,
-    uniform_light_dir: Vec3 // need to store here since i'm using this in FS instead of VS
+    uniform_lights: Vec<Light> // in view space
 }
 
 impl<T: ColorSpace + Copy> TangentNormalShader<T> {
-    pub fn new(model: Model<T>, transform: Transform) -> Self {
+    pub fn new(model: Model<T>, transform: Transform, lights: &[Light]) -> Self {
         TangentNormalShader {
-            varying_texture_coords: [Vec3::ZERO; 3],
-            varying_normals: [Vec3::ZERO; 3],
-            varying_ndc: [Vec3::ZERO; 3],
-            varying_uv: [Vec3::ZERO; 3],
             uniform_model: model,
             uniform_transform: transform,
-            uniform_light_dir: Vec3::ZERO 
+            uniform_lights: lights.iter().map(|light| light.view_transform(&transform)).collect()
         }
     }
 }
 
+pub struct TangentNormalVarying {
+    material: Option<usize>,
+    normals: [Vec3; 3], // need to interpolate normal for darboux transform computation
+    view_coords: [Vec3; 3],
+    uv: [Vec3; 3]
+}
+
 impl<T: ColorSpace + Copy> Shader<T> for TangentNormalShader<T> {
-    fn vertex(&mut self, obj_face: ObjFace, light_dir: Vec3) -> [Vec3; 3] {
-        self.uniform_light_dir = light_dir;
-        self.varying_uv = obj_face.texture_vertices;
+    type Varying = TangentNormalVarying;
+
+    fn vertex(&mut self, obj_face: ObjFace) -> ([Vec4; 3], TangentNormalVarying) {
+        let mut varying = TangentNormalVarying {
+            material: obj_face.material,
+            normals: [Vec3::ZERO; 3],
+            view_coords: [Vec3::ZERO; 3],
+            uv: obj_face.texture_vertices
+        };
+        let mut transformed_face = [Vec4::ZERO; 3];
         for i in 0..3 {
-            self.varying_texture_coords[i] = self.uniform_model
-                .texture_pixel_coords(obj_face.texture_vertices[i].x, obj_face.texture_vertices[i].y)
-                .extend(0.0);
-            self.varying_normals[i] = self.uniform_transform
-                .ndc_inv_tr_transform(obj_face.normals[i]);
-            self.varying_ndc[i] = self.uniform_transform
-                .ndc_transform(obj_face.vertices[i]);
+            varying.normals[i] = self.uniform_transform
+                .view_normal_transform(obj_face.normals[i]);
+            varying.view_coords[i] = self.uniform_transform
+                .view_transform(obj_face.vertices[i]);
+            transformed_face[i] = self.uniform_transform
+                .clip_transform(obj_face.vertices[i]);
             };
-        self.varying_ndc
+        (transformed_face, varying)
     } 
 
-    fn fragment(&self, bary_coords: Vec3, color: &mut T) -> bool {
+    fn fragment(&self, varying: &TangentNormalVarying, bary_coords: Vec3, color: &mut T) -> bool {
         // compute actual coords for corresponding pixel in texture + specular + normal images 
-        let interpolated_coords = bary_to_point(&bary_coords, &self.varying_uv);
+        let interpolated_coords = bary_to_point(&bary_coords, &varying.uv);
 
         // get tangent normal of corresponding pixel, compute tangent basis, then convert to actual normal
         let normal = {
-            // get raw tangent normal
-            let tang_norm_coords = self.uniform_model.tang_normal_pixel_coords(interpolated_coords.x, interpolated_coords.y);
-            let tangent_normal = self.uniform_model.get_tangent_normal(tang_norm_coords.x as usize, tang_norm_coords.y as usize).normalize();
-
             // get interpolated normal 
-            let interpolated_normal = bary_to_point(&bary_coords, &self.varying_normals).normalize();
+            let interpolated_normal = bary_to_point(&bary_coords, &varying.normals).normalize();
+
+            // get raw tangent normal (without one, just use the interpolated normal)
+            let tangent_normal = self.uniform_model
+                .get_tangent_normal(varying.material, interpolated_coords.truncate())
+                .unwrap_or(Vec3::Z)
+                .normalize();
 
             // compute darboux transform + 2 vectors that form tangent basis
             /**/
             let darboux_transform = Mat3::from_cols(
-                self.varying_ndc[1] - self.varying_ndc[0],
-                self.varying_ndc[2] - self.varying_ndc[0],
+                varying.view_coords[1] - varying.view_coords[0],
+                varying.view_coords[2] - varying.view_coords[0],
                 interpolated_normal
             ).transpose();
                         
 
             /* 
-            let ndc_tri = Mat3::from_cols(self.varying_ndc[0], self.varying_ndc[1], self.varying_ndc[2]);
+            let view_tri = Mat3::from_cols(varying.view_coords[0], varying.view_coords[1], varying.view_coords[2]);
             let darboux_transform = Mat3::from_cols(
-                ndc_tri.row(1) - ndc_tri.row(0),
-                ndc_tri.row(2) - ndc_tri.row(0),
+                view_tri.row(1) - view_tri.row(0),
+                view_tri.row(2) - view_tri.row(0),
                 interpolated_normal
             ).transpose();*/
             
              
             /* */
             let basis_i = darboux_transform.inverse() * Vec3::new(
-                self.varying_uv[0].y - self.varying_uv[0].x,
-                self.varying_uv[0].z - self.varying_uv[0].x,
+                varying.uv[0].y - varying.uv[0].x,
+                varying.uv[0].z - varying.uv[0].x,
                 0.0 
             );
             let basis_j = darboux_transform.inverse() * Vec3::new(
-                self.varying_uv[1].y - self.varying_uv[1].x,
-                self.varying_uv[1].z - self.varying_uv[1].x,
+                varying.uv[1].y - varying.uv[1].x,
+                varying.uv[1].z - varying.uv[1].x,
                 0.0 
             );
             
 
             /* 
             let basis_i = darboux_transform.inverse() * Vec3::new(
-                self.varying_uv[1].x - self.varying_uv[0].x,
-                self.varying_uv[2].x - self.varying_uv[0].x,
+                varying.uv[1].x - varying.uv[0].x,
+                varying.uv[2].x - varying.uv[0].x,
                 0.0 
             );
             let basis_j = darboux_transform.inverse() * Vec3::new(
-                self.varying_uv[1].y - self.varying_uv[0].y,
-                self.varying_uv[2].y - self.varying_uv[0].y,
+                varying.uv[1].y - varying.uv[0].y,
+                varying.uv[2].y - varying.uv[0].y,
                 0.0 
             );
             */
@@ -111,16 +118,13 @@ impl<T: ColorSpace + Copy> Shader<T> for TangentNormalShader<T> {
         };
         //println!("{bary_coords:?}   ->   {normal}");
         
-        // transform light vector into ndc
-        let light = self.uniform_transform
-            .ndc_transform(self.uniform_light_dir)
-            .normalize();
+        // sum up every light (already in view space)
+        let position = bary_to_point(&bary_coords, &varying.view_coords);
+        let intensity = accumulate_diffuse(&self.uniform_lights, position, normal);
 
         // shade the color
-        let intensity = f32::max(0.0, normal.dot(light));
-        let texture_pixel_coords = self.uniform_model.texture_pixel_coords(interpolated_coords.x, interpolated_coords.y);
-        //*color = self.uniform_model.get_texture_color(texture_pixel_coords.x as usize, texture_pixel_coords.y as usize);
-        color.shade(intensity);
+        //*color = self.uniform_model.get_texture_color(varying.material, interpolated_coords.truncate());
+        apply_light(color, Vec3::ZERO, intensity);
         false
     }
 }
\ No newline at end of file
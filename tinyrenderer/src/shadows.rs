@@ -0,0 +1,356 @@
+use crate::tgaimage::*;
+use crate::framebuffer::{DepthBuffer, Framebuffer};
+use crate::shaders::{DepthShader, Shader};
+use crate::rasterizer::{draw_triangle, CullMode, DrawStats, RasterState};
+use crate::transform::{initialize_transform, viewport, Projection, Transform};
+use crate::obj::Mesh;
+use glam::*;
+
+/// How a shadow map gets sampled.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShadowFilter {
+    /// A single lookup, ie hard edges.
+    Hard,
+    /// Percentage-closer filtering over a `kernel` x `kernel` grid of texels (eg 3 or 5), ie soft edges.
+    /// Even kernels work too: their grid sits between texels, half a texel off the point.
+    Pcf { kernel: usize },
+    /// Percentage-closer filtering over `samples` points (up to POISSON_DISK's) of a Poisson disk, `radius` texels wide.
+    /// The disk is rotated per fragment, which trades the grid's banding for noise.
+    Poisson { samples: usize, radius: f32 }
+}
+
+/// Shadow mapping settings. Biases are in shadow map texels, so they don't depend on the light's projection.
+#[derive(Clone, Copy, Debug)]
+pub struct ShadowSettings {
+    pub filter: ShadowFilter,
+    pub constant_bias: f32, // depth bias everywhere
+    pub slope_bias: f32,    // depth bias scaled by how steep the surface is to the light (ie how fast its depth changes per texel)
+    pub normal_offset: f32, // how far to push the lookup out along the normal (more so at grazing angles)
+    pub min_light: f32      // how much light is left in full shadow
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        ShadowSettings {
+            filter: ShadowFilter::Pcf { kernel: 3 },
+            constant_bias: 0.5,
+            slope_bias: 1.0,
+            normal_offset: 1.0,
+            min_light: 0.3
+        }
+    }
+}
+
+/// The usual 16 point Poisson disk, in [-1, 1].
+pub const POISSON_DISK: [Vec2; 16] = [
+    Vec2::new(-0.942016, -0.399062), Vec2::new(0.945586, -0.768907),
+    Vec2::new(-0.094184, -0.929389), Vec2::new(0.344959, 0.293878),
+    Vec2::new(-0.915886, 0.457714), Vec2::new(-0.815442, -0.879125),
+    Vec2::new(-0.382775, 0.276768), Vec2::new(0.974844, 0.756484),
+    Vec2::new(0.443233, -0.975116), Vec2::new(0.53743, -0.473734),
+    Vec2::new(-0.264969, -0.41893), Vec2::new(0.791975, 0.190902),
+    Vec2::new(-0.241888, 0.997065), Vec2::new(-0.8141, 0.914376),
+    Vec2::new(0.199841, 0.786414), Vec2::new(0.143832, -0.141)
+];
+
+// steeper than this (ie ~84 degrees) and the slope bias stops growing
+const MAX_SLOPE: f32 = 10.0;
+
+impl ShadowSettings {
+    /// Where to look a point up in the shadow map: pushed out along its (geometric, normalized) normal by `normal_offset` texels,
+    /// scaled by how grazing the light (a normalized direction towards it) is. `texel_size` is a texel's size at the point, in the same units.
+    pub fn offset_point(&self, point: Vec3, normal: Vec3, light: Vec3, texel_size: f32) -> Vec3 {
+        let grazing = 1.0 - normal.dot(light).clamp(0.0, 1.0);
+        point + normal * (self.normal_offset * texel_size * grazing)
+    }
+
+    /// The depth bias for a surface with the given (geometric, normalized) normal, in shadow map depth units,
+    /// given how much depth a texel's worth of distance is (or in distance, given a texel's size).
+    pub fn depth_bias(&self, normal: Vec3, light: Vec3, depth_per_texel: f32) -> f32 {
+        let cos = normal.dot(light).clamp(0.0, 1.0);
+        let tan = (1.0 - cos * cos).sqrt() / cos.max(f32::EPSILON);
+        (self.constant_bias + self.slope_bias * tan.min(MAX_SLOPE)) * depth_per_texel
+    }
+
+    /// How lit a point is (in [0, 1]), given its shadow map screen coords (x + y in texels, and z), and its depth bias.
+    /// Anything outside of the map (or beyond its depth range) is lit.
+    pub fn visibility(&self, shadow_map: &DepthBuffer, coords: Vec3, bias: f32) -> f32 {
+        let lit = |offset: Vec2| {
+            let (x, y) = (coords.x + offset.x, coords.y + offset.y);
+            (x >= 0.0 && y >= 0.0)
+                .then(|| shadow_map.get(x as usize, y as usize))
+                .flatten()
+                .map_or(1.0, |depth| f32::from(depth <= coords.z + bias)) // (greater z is closer)
+        };
+
+        match self.filter {
+            ShadowFilter::Hard => lit(Vec2::ZERO),
+            ShadowFilter::Pcf { kernel } => {
+                // centred on the point, so even kernels sample half a texel off it either way
+                let kernel = kernel.max(1);
+                let offset = move |i: usize| i as f32 - (kernel - 1) as f32 / 2.0;
+                let offsets = (0..kernel).flat_map(|y| (0..kernel).map(move |x| Vec2::new(offset(x), offset(y))));
+                offsets.map(lit).sum::<f32>() / (kernel * kernel) as f32
+            },
+            ShadowFilter::Poisson { samples, radius } => {
+                // rotate the disk by a hash of where the point is, so neighbouring fragments sample differently
+                let angle = (coords.xy().dot(Vec2::new(12.9898, 78.233)).sin() * 43758.547).fract() * std::f32::consts::TAU;
+                let rotation = Mat2::from_angle(angle);
+                let samples = samples.clamp(1, POISSON_DISK.len());
+                POISSON_DISK[..samples].iter().map(|&p| lit(rotation * p * radius)).sum::<f32>() / samples as f32
+            }
+        }
+    }
+
+    /// The light left once shadowed, from `min_light` (fully in shadow) to 1.
+    pub fn shade(&self, visibility: f32) -> f32 {
+        self.min_light + (1.0 - self.min_light) * visibility
+    }
+}
+
+
+// each cube face's view direction + up, in the usual cube map order (+x, -x, +y, -y, +z, -z)
+const CUBE_FACES: [(Vec3, Vec3); 6] = [
+    (Vec3::X, Vec3::NEG_Y), (Vec3::NEG_X, Vec3::NEG_Y),
+    (Vec3::Y, Vec3::Z), (Vec3::NEG_Y, Vec3::NEG_Z),
+    (Vec3::Z, Vec3::NEG_Y), (Vec3::NEG_Z, Vec3::NEG_Y)
+];
+
+// how many texels each face sees past its 90 degrees, so filtering near a face's edges still has depth to sample
+const CUBE_MARGIN: f32 = 4.0;
+
+/// A point light's shadow map: its depth in all six directions (+x, -x, +y, -y, +z, -z), each from a 90 degree perspective view.
+/// Lookups pick the face from the light-to-fragment vector, like a cube map's.
+#[derive(Clone)]
+pub struct CubeShadowMap {
+    pub position: Vec3,
+    pub transforms: [Mat4; 6], // object space -> each face's screen space (ie their whole transforms)
+    pub faces: Vec<DepthBuffer>,
+    texel_scale: f32 // a texel's size, per unit of distance (along the face's axis) from the light
+}
+
+impl CubeShadowMap {
+    /// Renders `mesh`'s depth from `position` (eg a point light's, in object space), into six `size` x `size` faces,
+    /// which see from `near` to `far`.
+    pub fn render(mesh: &Mesh, position: Vec3, size: usize, near: f32, far: f32) -> Self {
+        let texels = size as f32;
+        let half_width = 1.0 + 2.0 * CUBE_MARGIN / texels; // tan(fov / 2), ie a bit more than 1
+        let projection = Projection::Perspective { fov_y: 2.0 * half_width.atan().to_degrees(), near, far };
+        // (each face renders to all of its texels, not just the middle 3/4 like `initialize_transform`'s viewport)
+        let transforms = CUBE_FACES.map(|(dir, up)| Transform {
+            viewport: viewport(0, 0, size, size),
+            ..initialize_transform(size, size, position, position + dir, up, projection)
+        });
+
+        let faces = transforms.iter().map(|transform| render_depth(mesh, transform, size)).collect();
+
+        CubeShadowMap {
+            position,
+            transforms: transforms.map(|transform| transform.get_whole_transform()),
+            faces,
+            texel_scale: 2.0 * half_width / texels
+        }
+    }
+
+    /// Which face looks towards `dir` (from the light), going by its major axis.
+    pub fn face_index(dir: Vec3) -> usize {
+        let abs = dir.abs();
+        let (axis, value) = if abs.x >= abs.y && abs.x >= abs.z {
+            (0, dir.x)
+        } else if abs.y >= abs.z {
+            (1, dir.y)
+        } else {
+            (2, dir.z)
+        };
+        axis * 2 + usize::from(value < 0.0)
+    }
+
+    /// How lit a point (with the given geometric, normalized normal) is by the light, in [0, 1].
+    /// Unlike a directional light's map, texels grow with distance from the light, so the biases are worked out per point.
+    pub fn visibility(&self, settings: &ShadowSettings, point: Vec3, normal: Vec3) -> f32 {
+        let to_point = point - self.position;
+        let light = -to_point.normalize_or_zero();
+        let face = Self::face_index(to_point);
+        let texel_size = to_point.abs().max_element() * self.texel_scale;
+
+        // push the point out along its normal, then towards the light by the depth bias (in object space, since depth isn't linear here)
+        let point = settings.offset_point(point, normal, light, texel_size);
+        let point = point + light * settings.depth_bias(normal, light, texel_size);
+
+        let coords = self.transforms[face].project_point3(point);
+        settings.visibility(&self.faces[face], coords, 0.0)
+    }
+}
+
+
+/// Cascaded shadow map settings.
+#[derive(Clone, Copy, Debug)]
+pub struct CascadeConfig {
+    pub cascades: usize,   // how many slices the view frustum gets split into
+    pub size: usize,       // of each cascade's shadow map
+    pub max_distance: f32, // how far from the camera shadows go (the frustum's far plane is usually much further than that)
+    pub lambda: f32,       // how the splits are spaced: from 0 (evenly) to 1 (logarithmically, ie more cascades close up)
+    pub blend: f32         // how much of a cascade (at its far end) fades into the next, as a fraction of its depth range (0 for none)
+}
+
+impl Default for CascadeConfig {
+    fn default() -> Self {
+        CascadeConfig {
+            cascades: 4,
+            size: 1024,
+            max_distance: 20.0,
+            lambda: 0.75,
+            blend: 0.1
+        }
+    }
+}
+
+/// What each cascade gets tinted by, for debugging (it repeats past 4 cascades).
+pub const CASCADE_COLORS: [Vec3; 4] = [
+    Vec3::new(1.0, 0.4, 0.4), Vec3::new(0.4, 1.0, 0.4),
+    Vec3::new(0.4, 0.4, 1.0), Vec3::new(1.0, 1.0, 0.4)
+];
+
+/// A directional light's shadow map, split into cascades: the camera's view frustum gets sliced by distance,
+/// and each slice gets its own shadow map, fitted as tightly as it can be. So close up shadows get as many texels as far away ones,
+/// for however big the scene is.
+#[derive(Clone)]
+pub struct CascadedShadowMap {
+    pub splits: Vec<f32>,      // the view space distance where each cascade ends
+    pub transforms: Vec<Mat4>, // object space -> each cascade's screen space
+    pub maps: Vec<DepthBuffer>,
+    texel_sizes: Vec<f32>,     // in object space
+    depth_per_texels: Vec<f32>,
+    model_view: Mat4,          // the camera's
+    light: Vec3,               // towards the light, normalized
+    blend: f32
+}
+
+impl CascadedShadowMap {
+    /// Renders `mesh`'s depth from `light` (a direction towards it, in object space), for the view frustum of `camera`.
+    pub fn render(mesh: &Mesh, camera: &Transform, light: Vec3, config: &CascadeConfig) -> Self {
+        let light = light.normalize();
+        let cascades = config.cascades.max(1);
+
+        // the frustum's corners (in object space), near then far, + how far from the camera each plane is
+        let ndc_to_object = (camera.projection * camera.model_view).inverse();
+        let ndc_to_view = camera.projection.inverse();
+        let corners = |z: f32| [Vec2::new(-1.0, -1.0), Vec2::new(1.0, -1.0), Vec2::new(-1.0, 1.0), Vec2::new(1.0, 1.0)]
+            .map(|xy| ndc_to_object.project_point3(xy.extend(z)));
+        let (near_corners, far_corners) = (corners(-1.0), corners(1.0));
+        let near = -ndc_to_view.project_point3(Vec3::new(0.0, 0.0, -1.0)).z;
+        let frustum_far = -ndc_to_view.project_point3(Vec3::new(0.0, 0.0, 1.0)).z;
+        let far = frustum_far.min(config.max_distance);
+
+        // "practical" splits, ie somewhere between even + logarithmic ones
+        let splits: Vec<f32> = (1..=cascades)
+            .map(|i| {
+                let fraction = i as f32 / cascades as f32;
+                let logarithmic = near * (far / near).powf(fraction);
+                let uniform = near + (far - near) * fraction;
+                config.lambda * logarithmic + (1.0 - config.lambda) * uniform
+            })
+            .collect();
+
+        // the light looks along -light, from anywhere (it's orthographic)
+        let up = if light.y.abs() > 0.99 { Vec3::X } else { Vec3::Y };
+        let light_view = initialize_transform(config.size, config.size, Vec3::ZERO, -light, up, Projection::default()).model_view;
+        // (casters between a slice and the light must make it into its map too, so near reaches back to the whole mesh)
+        let mesh_nearest = mesh.positions.iter().map(|&p| light_view.transform_point3(p).z).fold(f32::MIN, f32::max);
+
+        let mut start = near;
+        let (mut transforms, mut maps, mut texel_sizes, mut depth_per_texels) = (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+        for &end in &splits {
+            // this slice's corners, fitted with a box in light space
+            let slice: Vec<Vec3> = [start, end]
+                .iter()
+                .flat_map(|distance| {
+                    let t = (distance - near) / (frustum_far - near);
+                    near_corners.iter().zip(far_corners).map(move |(&n, f)| n.lerp(f, t))
+                })
+                .collect();
+            let (min, max) = slice
+                .iter()
+                .map(|&p| light_view.transform_point3(p))
+                .fold((Vec3::MAX, Vec3::MIN), |(min, max), p| (min.min(p), max.max(p)));
+            let projection = Projection::Orthographic {
+                left: min.x, right: max.x, bottom: min.y, top: max.y,
+                near: -mesh_nearest.max(max.z), far: -min.z
+            };
+            let transform = initialize_transform(config.size, config.size, Vec3::ZERO, -light, up, projection);
+            let whole_transform = transform.get_whole_transform();
+
+            // (as in ShadowShader, the rows of the transform scale object space into texels + depth)
+            let texel_size = 1.0 / whole_transform.row(0).truncate().length();
+            texel_sizes.push(texel_size);
+            depth_per_texels.push(whole_transform.row(2).truncate().length() * texel_size);
+            maps.push(render_depth(mesh, &transform, config.size));
+            transforms.push(whole_transform);
+            start = end;
+        }
+
+        CascadedShadowMap {
+            splits,
+            transforms,
+            maps,
+            texel_sizes,
+            depth_per_texels,
+            model_view: camera.model_view,
+            light,
+            blend: config.blend
+        }
+    }
+
+    /// Which cascade covers a point (in object space), if any, + how far it's faded into the next one (in [0, 1]).
+    pub fn cascade(&self, point: Vec3) -> Option<(usize, f32)> {
+        let distance = -self.model_view.transform_point3(point).z;
+        let idx = self.splits.iter().position(|&split| distance <= split)?;
+
+        let start = if idx == 0 { 0.0 } else { self.splits[idx - 1] };
+        let blend_start = self.splits[idx] - (self.splits[idx] - start) * self.blend;
+        let fade = if idx + 1 < self.splits.len() && distance > blend_start {
+            (distance - blend_start) / (self.splits[idx] - blend_start)
+        } else {
+            0.0
+        };
+        Some((idx, fade))
+    }
+
+    /// How lit a point (with the given geometric, normalized normal) is by the light, in [0, 1].
+    /// Points past the last cascade are lit.
+    pub fn visibility(&self, settings: &ShadowSettings, point: Vec3, normal: Vec3) -> f32 {
+        let lit = |idx: usize| {
+            let offset = settings.offset_point(point, normal, self.light, self.texel_sizes[idx]);
+            let bias = settings.depth_bias(normal, self.light, self.depth_per_texels[idx]);
+            settings.visibility(&self.maps[idx], self.transforms[idx].project_point3(offset), bias)
+        };
+
+        match self.cascade(point) {
+            None => 1.0,
+            Some((idx, fade)) if fade > 0.0 => lit(idx) * (1.0 - fade) + lit(idx + 1) * fade,
+            Some((idx, _)) => lit(idx)
+        }
+    }
+
+    /// A point's cascade color (from CASCADE_COLORS, blended like its shadows), or white past the last cascade.
+    pub fn debug_color(&self, point: Vec3) -> Vec3 {
+        let color = |idx: usize| CASCADE_COLORS[idx % CASCADE_COLORS.len()];
+        self.cascade(point).map_or(Vec3::ONE, |(idx, fade)| {
+            if fade > 0.0 { color(idx).lerp(color(idx + 1), fade) } else { color(idx) }
+        })
+    }
+}
+
+// renders a mesh's depth, as seen by `transform`
+fn render_depth(mesh: &Mesh, transform: &Transform, size: usize) -> DepthBuffer {
+    // front faces are culled, so surfaces aren't compared against their own depth (see main)
+    let state = RasterState { cull_mode: CullMode::Front, ..RasterState::default() };
+    let mut stats = DrawStats::default();
+    let mut framebuffer: Framebuffer<Grayscale> = Framebuffer::new(size, size, 1);
+    let mut shader = DepthShader::new(*transform);
+    for obj_face in mesh {
+        let (clip_coords, varying) = Shader::<Grayscale>::vertex(&mut shader, obj_face);
+        draw_triangle(&mut framebuffer, &shader, &varying, &state, transform, clip_coords, &mut stats);
+    }
+    framebuffer.depth
+}
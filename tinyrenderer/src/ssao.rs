@@ -0,0 +1,194 @@
+use crate::tgaimage::*;
+use crate::transform::Transform;
+use crate::framebuffer::DepthBuffer;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use glam::*;
+
+/// Width (and height) of the tile of random rotations, which gets repeated over the screen.
+pub const NOISE_SIZE: usize = 4;
+
+/// Screen-space ambient occlusion settings (distances are in view space).
+#[derive(Clone, Copy, Debug)]
+pub struct SsaoConfig {
+    pub radius: f32,        // how far around a point to look for occluders
+    pub samples: usize,     // points in the hemisphere kernel
+    pub bias: f32,          // how far in front of a sample the depth buffer must be to occlude it (ie against self-occlusion)
+    pub blur_radius: usize  // in pixels; 0 leaves the noise in
+}
+
+impl Default for SsaoConfig {
+    fn default() -> Self {
+        SsaoConfig {
+            radius: 0.1,
+            samples: 16,
+            bias: 0.005,
+            blur_radius: NOISE_SIZE / 2
+        }
+    }
+}
+
+/// Screen-space ambient occlusion, as a post-process over a depth buffer (and a normal buffer, if there is one).
+///
+/// For every pixel, points in a hemisphere around its normal get projected back onto the screen;
+/// the ones behind what the depth buffer has there are occluded. The hemisphere is randomly rotated
+/// per pixel (from a small tiled noise texture), so few samples suffice, and the resulting noise is then
+/// blurred away, without blurring across depth discontinuities (ie bilaterally).
+pub struct Ssao {
+    pub config: SsaoConfig,
+    kernel: Vec<Vec3>, // in tangent space, ie around +z
+    noise: Vec<Vec3>   // NOISE_SIZE x NOISE_SIZE rotations around z
+}
+
+impl Ssao {
+    pub fn new(config: SsaoConfig) -> Self {
+        // seeded, so the same scene gives the same image
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let kernel = (0..config.samples)
+            .map(|i| {
+                let sample = Vec3::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), rng.gen_range(0.0..1.0))
+                    .normalize_or_zero()
+                    * rng.gen::<f32>();
+                // bunch samples up towards the centre, where occluders matter most
+                let scale = i as f32 / config.samples as f32;
+                sample * (0.1 + 0.9 * scale * scale)
+            })
+            .collect();
+        let noise = (0..NOISE_SIZE * NOISE_SIZE)
+            .map(|_| Vec3::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), 0.0))
+            .collect();
+
+        Ssao { config, kernel, noise }
+    }
+
+    /// How unoccluded each pixel is, from black (fully occluded) to white; pixels with nothing drawn are white.
+    /// `depth` is the camera's depth buffer, and `normals` (if any) are per pixel, in view space.
+    pub fn occlusion(&self, depth: &DepthBuffer, normals: Option<&[Vec3]>, transform: &Transform) -> Image<Grayscale> {
+        let (width, height) = (depth.width, depth.height);
+        let view_to_screen = transform.viewport * transform.projection;
+        let screen_to_view = view_to_screen.inverse();
+
+        // back into view space
+        let positions: Vec<Option<Vec3>> = depth.data
+            .iter()
+            .enumerate()
+            .map(|(idx, &z)| {
+                let (x, y) = (idx % width, idx / width);
+                (z != depth.clear_value).then(|| screen_to_view.project_point3(Vec3::new(x as f32 + 0.5, y as f32 + 0.5, z)))
+            })
+            .collect();
+        let position = |x: i32, y: i32| {
+            (x >= 0 && y >= 0 && (x as usize) < width && (y as usize) < height)
+                .then(|| positions[x as usize + y as usize * width])
+                .flatten()
+        };
+
+        let mut occlusion = vec![1.0; width * height];
+        for (idx, ao) in occlusion.iter_mut().enumerate() {
+            let (x, y) = ((idx % width) as i32, (idx / width) as i32);
+            let Some(p) = positions[idx] else { continue };
+            let normal = match normals {
+                Some(normals) => normals[idx],
+                None => reconstruct_normal(p, [position(x - 1, y), position(x + 1, y)], [position(x, y - 1), position(x, y + 1)])
+            };
+            if normal == Vec3::ZERO {
+                continue;
+            }
+
+            // tangent space, rotated around the normal by this pixel's noise
+            let random = self.noise[(x as usize % NOISE_SIZE) + (y as usize % NOISE_SIZE) * NOISE_SIZE];
+            let tangent = (random - normal * random.dot(normal)).normalize_or_zero();
+            let tangent = if tangent == Vec3::ZERO { normal.any_orthonormal_vector() } else { tangent };
+            let tbn = Mat3::from_cols(tangent, normal.cross(tangent), normal);
+
+            let occluded: f32 = self.kernel
+                .iter()
+                .map(|&sample| {
+                    let sample = p + tbn * sample * self.config.radius;
+                    let screen = view_to_screen.project_point3(sample);
+                    let Some(occluder) = position(screen.x.floor() as i32, screen.y.floor() as i32) else { return 0.0 };
+
+                    // (the camera looks down -z, so closer is greater z), and far away occluders fade out
+                    let range = smoothstep(self.config.radius / (p.z - occluder.z).abs());
+                    f32::from(occluder.z >= sample.z + self.config.bias) * range
+                })
+                .sum();
+            *ao = 1.0 - occluded / self.kernel.len().max(1) as f32;
+        }
+
+        let occlusion = self.blur(&occlusion, &positions, width, height);
+
+        let mut image = Image::new(width, height);
+        for (pixel, ao) in image.data.iter_mut().zip(occlusion) {
+            *pixel = Grayscale { i: (ao.clamp(0.0, 1.0) * 255.0).round() as u8 };
+        }
+        image
+    }
+
+    // averages out the noise, but only over pixels at a similar depth (so edges stay sharp)
+    fn blur(&self, occlusion: &[f32], positions: &[Option<Vec3>], width: usize, height: usize) -> Vec<f32> {
+        let radius = self.config.blur_radius as i32;
+        if radius == 0 {
+            return occlusion.to_vec();
+        }
+        let spatial_sigma = radius as f32;
+        let depth_sigma = self.config.radius * 0.5;
+
+        let mut blurred = occlusion.to_vec();
+        for (idx, out) in blurred.iter_mut().enumerate() {
+            let Some(p) = positions[idx] else { continue };
+            let (x, y) = ((idx % width) as i32, (idx / width) as i32);
+
+            let (mut sum, mut total_weight) = (0.0, 0.0);
+            for dy in -radius..=radius {
+                for dx in -radius..=radius {
+                    let (nx, ny) = (x + dx, y + dy);
+                    if nx < 0 || ny < 0 || nx as usize >= width || ny as usize >= height {
+                        continue;
+                    }
+                    let neighbour = nx as usize + ny as usize * width;
+                    let Some(q) = positions[neighbour] else { continue };
+
+                    let dz = (q.z - p.z) / depth_sigma;
+                    let weight = (-((dx*dx + dy*dy) as f32) / (2.0 * spatial_sigma * spatial_sigma) - dz * dz / 2.0).exp();
+                    sum += occlusion[neighbour] * weight;
+                    total_weight += weight;
+                }
+            }
+            *out = sum / total_weight; // (the pixel itself always has weight 1)
+        }
+        blurred
+    }
+}
+
+/// The ambient occlusion (in [0, 1]) at a screen space point, eg to scale a shader's ambient term by.
+/// Points outside of the image are unoccluded.
+pub fn ambient_occlusion(occlusion: &Image<Grayscale>, screen: Vec3) -> f32 {
+    (screen.x >= 0.0 && screen.y >= 0.0)
+        .then(|| occlusion.get(screen.x as usize, screen.y as usize).ok())
+        .flatten()
+        .map_or(1.0, |ao| ao.i as f32 / 255.0)
+}
+
+// a normal from the neighbouring positions (on either side, in x and y), using whichever side is closest in depth,
+// so it doesn't bend over edges. Faces the camera.
+fn reconstruct_normal(p: Vec3, x_neighbours: [Option<Vec3>; 2], y_neighbours: [Option<Vec3>; 2]) -> Vec3 {
+    let closest = |[before, after]: [Option<Vec3>; 2]| {
+        let before = before.map(|q| p - q);
+        let after = after.map(|q| q - p);
+        match (before, after) {
+            (Some(b), Some(a)) => Some(if b.z.abs() < a.z.abs() { b } else { a }),
+            (b, a) => b.or(a)
+        }
+    };
+    let (Some(dx), Some(dy)) = (closest(x_neighbours), closest(y_neighbours)) else { return Vec3::ZERO };
+
+    let normal = dx.cross(dy).normalize_or_zero();
+    if normal.dot(-p) < 0.0 { -normal } else { normal }
+}
+
+// 0 -> 1 smoothly, over [0, 1]
+fn smoothstep(x: f32) -> f32 {
+    let x = x.clamp(0.0, 1.0);
+    x * x * (3.0 - 2.0 * x)
+}
@@ -1,6 +1,6 @@
-use std::{fmt::Debug, fs::File, io::{self, BufWriter, Read, Write}};
+use std::{error::Error, fmt::{self, Debug, Display}, fs::{self, File}, io::{self, BufWriter, Write}, path::Path};
 
-use tinytga::RawTga;
+use crate::png::{encode_png, PngColorType};
 
 ///// Colorspaces
 pub trait ColorSpace {
@@ -8,8 +8,10 @@ pub trait ColorSpace {
     fn white() -> Self;
     fn from_rgba(color: RGBA) -> Self;
     fn shade(&mut self, intensity: f32);
-    fn to_vec(&self) -> Vec<u8>;
+    fn to_vec(&self) -> Vec<u8>; // channels in r, g, b, a order (ie only those the colorspace has)
     fn from_vec(&mut self, colors: Vec<u8>) -> Result<(), String>;
+    fn alpha(&self) -> f32; // in [0, 1]; colorspaces without an alpha channel are always opaque
+    fn set_alpha(&mut self, alpha: f32);
     const BPP: u8;
 }
 
@@ -39,7 +41,7 @@ impl ColorSpace for Grayscale {
         Grayscale {i: 255}
     }
     fn from_rgba(color: RGBA) -> Self {
-        Grayscale {i: *vec![color.r, color.g, color.b, color.a].iter().max().unwrap()}
+        Grayscale {i: color.r.max(color.g).max(color.b)}
     }
     fn shade(&mut self, intensity: f32) {
         if intensity > 1.0 { return }
@@ -57,6 +59,10 @@ impl ColorSpace for Grayscale {
         self.i = colors[0];
         Ok(())
     }
+    fn alpha(&self) -> f32 {
+        1.0
+    }
+    fn set_alpha(&mut self, _alpha: f32) {}
     const BPP: u8 = 1;
 }
 
@@ -90,6 +96,10 @@ impl ColorSpace for RGB {
         self.b = colors[2];
         Ok(())
     }
+    fn alpha(&self) -> f32 {
+        1.0
+    }
+    fn set_alpha(&mut self, _alpha: f32) {}
     const BPP: u8 = 3;
 }
 
@@ -109,10 +119,9 @@ impl ColorSpace for RGBA {
         self.r = ((self.r as f32) * intensity) as u8;
         self.g = ((self.g as f32) * intensity) as u8;
         self.b = ((self.b as f32) * intensity) as u8;
-        self.a = ((self.a as f32) * intensity) as u8;
     }
     fn to_vec(&self) -> Vec<u8> {
-        vec![self.r, self.g, self.b]
+        vec![self.r, self.g, self.b, self.a]
     }
     fn from_vec(&mut self, colors: Vec<u8>) -> Result<(), String> {
         if colors.len()!=4 { 
@@ -126,6 +135,12 @@ impl ColorSpace for RGBA {
         self.a = colors[3];
         Ok(())
     }
+    fn alpha(&self) -> f32 {
+        self.a as f32 / 255.0
+    }
+    fn set_alpha(&mut self, alpha: f32) {
+        self.a = (alpha.clamp(0.0, 1.0) * 255.0).round() as u8;
+    }
     const BPP: u8 = 4;
 }
 
@@ -134,6 +149,10 @@ impl ColorSpace for RGBA {
 const DEVELOPER_AREA_REF: [u8; 4] = [0, 0, 0, 0];
 const EXTENSION_AREA_REF: [u8; 4] = [0, 0, 0, 0];
 const FOOTER: [u8; 18] = *b"TRUEVISION-XFILE.\0";
+const HEADER_LENGTH: usize = 18;
+const FOOTER_LENGTH: usize = 26; // extension area ref + developer area ref + signature
+const EXTENSION_AREA_LENGTH: usize = 495;
+const ATTRIBUTES_TYPE_OFFSET: usize = 494; // within the extension area
 
 #[derive(Default)]
 #[repr(packed)]
@@ -153,6 +172,63 @@ struct Header {
     imagedescriptor: u8,
 }
 
+impl Header {
+    fn from_bytes(bytes: &[u8]) -> Result<Header, TgaError> {
+        if bytes.len() < HEADER_LENGTH {
+            return Err(TgaError::Truncated);
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i+1]]);
+        Ok(Header {
+            idlength: bytes[0],
+            colormaptype: bytes[1],
+            datatypecode: bytes[2],
+            colormaporigin: u16_at(3),
+            colormaplength: u16_at(5),
+            colormapdepth: bytes[7],
+            x_origin: u16_at(8),
+            y_origin: u16_at(10),
+            width: u16_at(12),
+            height: u16_at(14),
+            bitsperpixel: bytes[16],
+            imagedescriptor: bytes[17],
+        })
+    }
+}
+
+///// Errors
+
+/// Errors from reading a TGA file.
+#[derive(Debug)]
+pub enum TgaError {
+    Io(io::Error),
+    Truncated,
+    UnsupportedDataType(u8),
+    UnsupportedDepth(u8),
+    MissingColorMap,
+    ColorMapIndexOutOfRange(usize),
+}
+
+impl Display for TgaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TgaError::Io(err) => write!(f, "I/O error reading TGA file: {err}"),
+            TgaError::Truncated => write!(f, "TGA file ended before all of its data was read"),
+            TgaError::UnsupportedDataType(code) => write!(f, "Unsupported TGA data type: {code}"),
+            TgaError::UnsupportedDepth(depth) => write!(f, "Unsupported TGA pixel/color map depth: {depth}"),
+            TgaError::MissingColorMap => write!(f, "Color-mapped TGA file has no color map"),
+            TgaError::ColorMapIndexOutOfRange(idx) => write!(f, "TGA color map index is out of range: {idx}"),
+        }
+    }
+}
+
+impl Error for TgaError {}
+
+impl From<io::Error> for TgaError {
+    fn from(err: io::Error) -> Self {
+        TgaError::Io(err)
+    }
+}
+
 ///// Image
 
 // converts sized type to raw u8, for writing out
@@ -161,7 +237,6 @@ pub unsafe fn any_as_u8_slice<T: Sized>(p: &T) -> &[u8] {
 }
 
 /// Represents a TGA image.
-/// I'm too lazy to write a fn to read from file, use tinytga instead for that.
 #[derive(Clone)]
 pub struct Image <T: ColorSpace> {
     pub width: usize,
@@ -195,6 +270,22 @@ impl <T: ColorSpace + Copy> Image<T>  {
         Ok(self.data[x + y*self.width])
     }
 
+    /// Copies the image into RGB, dropping any alpha (grayscale goes to all three channels), eg to write a render out opaque.
+    pub fn to_rgb(&self) -> Image<RGB> {
+        Image {
+            width: self.width,
+            height: self.height,
+            data: self.data
+                .iter()
+                .map(|pixel| match pixel.to_vec()[..] {
+                    [i] => RGB { r: i, g: i, b: i },
+                    [r, g, b, ..] => RGB { r, g, b },
+                    _ => RGB::new()
+                })
+                .collect()
+        }
+    }
+
     fn data_vec(&self) -> Vec<u8> {
         self.data
             .iter()
@@ -202,6 +293,100 @@ impl <T: ColorSpace + Copy> Image<T>  {
             .copied()
             .collect::<Vec<u8>>()
     }
+    /// Reads a TGA file (uncompressed or RLE; true-color, grayscale or color-mapped).
+    /// Regardless of the file's origin, pixel (0, 0) of the resulting image is its top-left.
+    pub fn read_tga_file(filename: &str) -> Result<Self, TgaError> {
+        let bytes = fs::read(filename)?;
+        let header = Header::from_bytes(&bytes)?;
+        let (width, height) = (header.width as usize, header.height as usize);
+
+        let (color_mapped, rle) = match header.datatypecode {
+            1 => (true, false),
+            2 | 3 => (false, false),
+            9 => (true, true),
+            10 | 11 => (false, true),
+            code => return Err(TgaError::UnsupportedDataType(code))
+        };
+
+        // alpha is only meaningful if the descriptor has attribute bits for it,
+        // and (for TGA 2.0 files) the extension area doesn't say otherwise
+        let has_alpha = header.imagedescriptor & 0x0F > 0 
+            && !matches!(read_attributes_type(&bytes), Some(0) | Some(1));
+
+        // skip image ID, then read color map (if any)
+        let mut offset = HEADER_LENGTH + header.idlength as usize;
+        let color_map = if header.colormaptype == 1 {
+            let entry_size = bytes_per_pixel(header.colormapdepth)?;
+            let map_length = header.colormaplength as usize * entry_size;
+            let map_bytes = bytes.get(offset..offset + map_length).ok_or(TgaError::Truncated)?;
+            offset += map_length;
+            map_bytes
+                .chunks_exact(entry_size)
+                .map(|entry| decode_color(entry, header.colormapdepth, has_alpha))
+                .collect::<Vec<RGBA>>()
+        } else {
+            Vec::new()
+        };
+        if color_mapped && color_map.is_empty() {
+            return Err(TgaError::MissingColorMap);
+        }
+
+        let pixel_size = bytes_per_pixel(header.bitsperpixel)?;
+        let decode = |pixel: &[u8]| -> Result<RGBA, TgaError> {
+            if color_mapped {
+                let idx = if pixel_size == 1 { pixel[0] as usize } else { u16::from_le_bytes([pixel[0], pixel[1]]) as usize };
+                idx.checked_sub(header.colormaporigin as usize)
+                    .and_then(|i| color_map.get(i).copied())
+                    .ok_or(TgaError::ColorMapIndexOutOfRange(idx))
+            } else {
+                Ok(decode_color(pixel, header.bitsperpixel, has_alpha))
+            }
+        };
+
+        // pixels are stored in rows from the origin, which can be any of the corners
+        let top_origin = header.imagedescriptor & 0x20 != 0;
+        let right_origin = header.imagedescriptor & 0x10 != 0;
+        let mut image = Image::new(width, height);
+        let mut place = |n: usize, color: RGBA| {
+            let (col, row) = (n % width, n / width);
+            let x = if right_origin { width - 1 - col } else { col };
+            let y = if top_origin { row } else { height - 1 - row };
+            image.data[x + y*width] = T::from_rgba(color);
+        };
+
+        let n_pixels = width * height;
+        let mut current_pixel = 0;
+        while current_pixel < n_pixels {
+            // raw data is just one long chunk; RLE data has a 1 byte header per chunk
+            let (chunk_length, repeated) = if rle {
+                let chunk_header = *bytes.get(offset).ok_or(TgaError::Truncated)?;
+                offset += 1;
+                ((chunk_header & 0x7F) as usize + 1, chunk_header & 0x80 != 0)
+            } else {
+                (n_pixels, false)
+            };
+            let chunk_length = chunk_length.min(n_pixels - current_pixel);
+
+            if repeated {
+                let pixel = bytes.get(offset..offset + pixel_size).ok_or(TgaError::Truncated)?;
+                offset += pixel_size;
+                let color = decode(pixel)?;
+                for n in current_pixel..current_pixel + chunk_length {
+                    place(n, color);
+                }
+            } else {
+                let chunk = bytes.get(offset..offset + chunk_length*pixel_size).ok_or(TgaError::Truncated)?;
+                offset += chunk_length*pixel_size;
+                for (i, pixel) in chunk.chunks_exact(pixel_size).enumerate() {
+                    place(current_pixel + i, decode(pixel)?);
+                }
+            }
+            current_pixel += chunk_length;
+        }
+
+        Ok(image)
+    }
+
     pub fn write_tga_file(&self, filename: &str, vflip: bool, rle: bool) -> io::Result<()> {
         let mut out = BufWriter::new(
             File::options()
@@ -221,7 +406,7 @@ impl <T: ColorSpace + Copy> Image<T>  {
             } else {
                 match rle { true => 10, false => 2 }
             },
-            imagedescriptor: if vflip { 0x00 } else { 0x20 },
+            imagedescriptor: if vflip { 0x00 } else { 0x20 } | if T::BPP == RGBA::BPP { 8 } else { 0 }, // alpha bits
             ..Default::default()
         };
 
@@ -281,34 +466,151 @@ impl <T: ColorSpace + Copy> Image<T>  {
             }])?;
             out.write(
                 &data[chunk_start
-                    ..chunk_start + (if raw { run_length * T::BPP } else { T::BPP }) as usize],
+                    ..chunk_start + (if raw { run_length as usize * T::BPP as usize } else { T::BPP as usize })],
             )?;
         }
         Ok(())
     }
-}       
-
-// converts tinytga image into our format   
-pub fn convert_from_tinytga<T>(image_path: &str) -> Image<T> where T: ColorSpace + Copy + Debug {
-    let mut data = Vec::<u8>::new(); 
-    File::open(image_path).unwrap().read_to_end(&mut data).unwrap();
-    let img = RawTga::from_slice(&data[..]).unwrap();
-    let (height, width) = (img.size().height, img.size().width);
-    let raw_pixels: Vec<_> = img.pixels().collect();
-    let mut new_pixels = vec![T::new(); (height*width) as usize];
-    
-    for pixel in raw_pixels {
-        let (x, y) = (pixel.position.x, pixel.position.y);
-        let color = RGBA {
-            b: (pixel.color & 0xFF) as u8,
-            g: ((pixel.color >> 8) & 0xFF) as u8,
-            r: ((pixel.color >> 16) & 0xFF) as u8,
-            a: ((pixel.color >> 24) & 0xFF) as u8
+
+    /// Writes the image in whichever format its extension says (tga, png, or ppm/pgm).
+    /// `vflip` is as in `write_tga_file`, ie set it if row 0 is the bottom of the image.
+    pub fn write_file(&self, filename: &str, vflip: bool) -> io::Result<()> {
+        let extension = Path::new(filename)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("tga") => self.write_tga_file(filename, vflip, false),
+            Some("png") => self.write_png_file(filename, vflip),
+            Some("ppm") | Some("pgm") => self.write_pnm_file(filename, vflip),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput, 
+                format!("Unsupported image format: {filename}")
+            ))
+        }
+    }
+
+    /// Writes the image as a PNG.
+    pub fn write_png_file(&self, filename: &str, vflip: bool) -> io::Result<()> {
+        let color_type = match T::BPP {
+            1 => PngColorType::Grayscale,
+            3 => PngColorType::Rgb,
+            _ => PngColorType::Rgba
         };
-        new_pixels[(x + y*width as i32) as usize] = T::from_rgba(color);
+        let data = self.rgb_data_vec();
+        let rows = rows_top_down(&data, self.width * T::BPP as usize, vflip);
+        fs::write(filename, encode_png(self.width, self.height, color_type, rows.into_iter()))
+    }
+
+    /// Writes the image as a binary PGM if it's grayscale, or a binary PPM otherwise (dropping alpha, if any).
+    pub fn write_pnm_file(&self, filename: &str, vflip: bool) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(filename)?);
+        let magic = if T::BPP == Grayscale::BPP { "P5" } else { "P6" };
+        write!(out, "{magic}\n{} {}\n255\n", self.width, self.height)?;
+
+        let data = self.rgb_data_vec();
+        for row in rows_top_down(&data, self.width * T::BPP as usize, vflip) {
+            if T::BPP == RGBA::BPP {
+                for pixel in row.chunks_exact(T::BPP as usize) {
+                    out.write_all(&pixel[..3])?;
+                }
+            } else {
+                out.write_all(row)?;
+            }
+        }
+        out.flush()
     }
 
-    let mut image: Image<T> = Image::new(width as usize, height as usize);
-    image.data = new_pixels;
-    image
-}
\ No newline at end of file
+    // image data in RGB(A) order, for formats other than TGA (which, like our pixels, is BGR(A))
+    fn rgb_data_vec(&self) -> Vec<u8> {
+        let mut data = self.data_vec();
+        if T::BPP >= 3 {
+            for pixel in data.chunks_exact_mut(T::BPP as usize) {
+                pixel.swap(0, 2);
+            }
+        }
+        data
+    }
+}
+
+/// Writes a buffer of floats (eg a zbuffer or shadowbuffer) as a grayscale PFM.
+/// `vflip` is as in `Image::write_tga_file`.
+pub fn write_pfm_file(filename: &str, width: usize, height: usize, data: &[f32], vflip: bool) -> io::Result<()> {
+    if data.len() != width * height {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Buffer has {} values, but should have {width} by {height}", data.len())
+        ));
+    }
+
+    let mut out = BufWriter::new(File::create(filename)?);
+    write!(out, "Pf\n{width} {height}\n-1.0\n")?; // negative scale means little-endian
+
+    // PFM goes from the bottom row up, unlike the others
+    let mut rows: Vec<&[f32]> = data.chunks_exact(width).collect();
+    if !vflip {
+        rows.reverse();
+    }
+    for row in rows {
+        for value in row {
+            out.write_all(&value.to_le_bytes())?;
+        }
+    }
+    out.flush()
+}
+
+// split image data into rows, ordered from the top of the image
+fn rows_top_down(data: &[u8], row_length: usize, vflip: bool) -> Vec<&[u8]> {
+    let mut rows: Vec<&[u8]> = data.chunks_exact(row_length).collect();
+    if vflip {
+        rows.reverse();
+    }
+    rows
+}
+
+// number of bytes used for a pixel/color map entry of the given bit depth
+fn bytes_per_pixel(depth: u8) -> Result<usize, TgaError> {
+    match depth {
+        8 => Ok(1),
+        15 | 16 => Ok(2),
+        24 => Ok(3),
+        32 => Ok(4),
+        _ => Err(TgaError::UnsupportedDepth(depth))
+    }
+}
+
+// decodes a single (non-color-mapped) pixel of the given bit depth
+// NOTE: 8 bits is grayscale, and 16 bits can be grayscale + alpha too, but we can't tell that from the depth;
+// this treats 16 bits as ARRRRRGG GGGBBBBB, as that's far more common
+fn decode_color(pixel: &[u8], depth: u8, has_alpha: bool) -> RGBA {
+    let scale_5_bits = |c: u16| ((c << 3) | (c >> 2)) as u8;
+    match depth {
+        8 => RGBA { b: pixel[0], g: pixel[0], r: pixel[0], a: 255 },
+        15 | 16 => {
+            let p = u16::from_le_bytes([pixel[0], pixel[1]]);
+            RGBA {
+                b: scale_5_bits(p & 0x1F),
+                g: scale_5_bits((p >> 5) & 0x1F),
+                r: scale_5_bits((p >> 10) & 0x1F),
+                a: if depth == 16 && has_alpha && p & 0x8000 == 0 { 0 } else { 255 }
+            }
+        },
+        24 => RGBA { b: pixel[0], g: pixel[1], r: pixel[2], a: 255 },
+        _ => RGBA { b: pixel[0], g: pixel[1], r: pixel[2], a: if has_alpha { pixel[3] } else { 255 } },
+    }
+}
+
+// reads the attributes type from a TGA 2.0 file's extension area, if it has one
+// (0 = no alpha, 1 = undefined alpha to ignore, 2 = undefined alpha to keep, 3 = alpha, 4 = premultiplied alpha)
+fn read_attributes_type(bytes: &[u8]) -> Option<u8> {
+    let footer = bytes.get(bytes.len().checked_sub(FOOTER_LENGTH)?..)?;
+    if footer[8..] != FOOTER {
+        return None;
+    }
+    let extension_offset = u32::from_le_bytes(footer[0..4].try_into().ok()?) as usize;
+    if extension_offset == 0 {
+        return None;
+    }
+    let extension_area = bytes.get(extension_offset..extension_offset + EXTENSION_AREA_LENGTH)?;
+    Some(extension_area[ATTRIBUTES_TYPE_OFFSET])
+}
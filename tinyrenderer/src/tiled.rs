@@ -0,0 +1,180 @@
+use crate::tgaimage::*;
+use crate::shaders::Shader;
+use crate::rasterizer::{bounding_box, rasterize, screen_triangles, shade_fragment, DrawStats, RasterState};
+use crate::transform::Transform;
+use crate::framebuffer::Framebuffer;
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use glam::*;
+
+/// Width + height of a tile, in pixels.
+pub const TILE_SIZE: usize = 64;
+
+/// A binned, tile-based rasterizer.
+///
+/// Triangles are first bucketed by which tiles (of TILE_SIZE x TILE_SIZE pixels) their bounding boxes overlap.
+/// Then `render` shades the tiles in parallel, each with its own piece of the z-buffer, so threads never share pixels.
+/// Within a tile, triangles are drawn in the order they were given,
+/// so the output is identical to calling `draw_triangle` on each of them in turn.
+pub struct TiledRasterizer<V> {
+    width: usize,
+    height: usize,
+    tiles_x: usize,
+    state: RasterState,
+    pub threads: usize,
+    stats: DrawStats,
+    triangles: Vec<BinnedTriangle>,
+    varyings: Vec<V>,
+    bins: Vec<Vec<u32>> // for each tile, indices into `triangles`
+}
+
+// a (piece of a clipped) triangle, in screen space, waiting to be rasterized
+struct BinnedTriangle {
+    screen_coords: [Vec4; 3],
+    bary_coords: [Vec3; 3],
+    varying: usize, // index into `varyings`
+    bboxmin: UVec2,
+    bboxmax: UVec2
+}
+
+// a rasterized tile, to be copied back into the framebuffer
+struct RenderedTile<T: ColorSpace + Copy> {
+    tile: usize,
+    framebuffer: Framebuffer<T>
+}
+
+impl<V: Sync> TiledRasterizer<V> {
+    /// Makes a tiled rasterizer for a `width` x `height` image, using as many threads as there are cores.
+    pub fn new(width: usize, height: usize, state: RasterState) -> Self {
+        let tiles_x = width.div_ceil(TILE_SIZE);
+        let tiles_y = height.div_ceil(TILE_SIZE);
+        TiledRasterizer {
+            width,
+            height,
+            tiles_x,
+            state,
+            threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            stats: DrawStats::default(),
+            triangles: Vec::new(),
+            varyings: Vec::new(),
+            bins: vec![Vec::new(); tiles_x * tiles_y]
+        }
+    }
+
+    /// Clips (and culls) a triangle (given its clip coordinates + varyings, ie as returned by the vertex shader),
+    /// then bins whatever is left, to be rasterized by `render`.
+    pub fn draw_triangle(&mut self, transform: &Transform, clip_coords: [Vec4; 3], varying: V) {
+        // (an empty target has no tiles to bin into)
+        if self.bins.is_empty() {
+            return;
+        }
+        let screen_triangles = screen_triangles(&self.state, transform, clip_coords, &mut self.stats);
+        if screen_triangles.is_empty() {
+            return;
+        }
+        self.varyings.push(varying);
+
+        for (screen_coords, bary_coords) in screen_triangles {
+            let (bboxmin, bboxmax) = bounding_box(&screen_coords, self.width, self.height);
+            let idx = self.triangles.len() as u32;
+            for tile_y in bboxmin.y as usize / TILE_SIZE ..= bboxmax.y as usize / TILE_SIZE {
+                for tile_x in bboxmin.x as usize / TILE_SIZE ..= bboxmax.x as usize / TILE_SIZE {
+                    self.bins[tile_x + tile_y*self.tiles_x].push(idx);
+                }
+            }
+            self.triangles.push(BinnedTriangle {
+                screen_coords,
+                bary_coords,
+                varying: self.varyings.len() - 1,
+                bboxmin,
+                bboxmax
+            });
+        }
+    }
+
+    /// Rasterizes + shades every binned triangle into `framebuffer`.
+    /// Tiles are shared out between `threads` threads, which take the next unrendered tile whenever they're free.
+    pub fn render<T, S>(&self, framebuffer: &mut Framebuffer<T>, shader: &S)
+    where
+        T: ColorSpace + Copy + Debug + Send + Sync,
+        S: Shader<T, Varying = V> + Sync {
+
+        let next_tile = AtomicUsize::new(0);
+        let framebuffer_ref = &*framebuffer;
+
+        let rendered: Vec<RenderedTile<T>> = thread::scope(|scope| {
+            let workers: Vec<_> = (0..self.threads.max(1))
+                .map(|_| scope.spawn(|| {
+                    let mut rendered = Vec::new();
+                    loop {
+                        let tile = next_tile.fetch_add(1, Ordering::Relaxed);
+                        if tile >= self.bins.len() {
+                            break;
+                        }
+                        if !self.bins[tile].is_empty() {
+                            rendered.push(self.render_tile(tile, framebuffer_ref, shader));
+                        }
+                    }
+                    rendered
+                }))
+                .collect();
+
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().unwrap())
+                .collect()
+        });
+
+        // copy the tiles back
+        for tile in rendered {
+            let (tilemin, _) = self.tile_bounds(tile.tile);
+            framebuffer.write_region(&tile.framebuffer, tilemin);
+        }
+    }
+
+    /// How many triangles were drawn (and culled) since the last `clear`.
+    pub fn stats(&self) -> DrawStats {
+        self.stats
+    }
+
+    /// Removes every binned triangle (and resets the stats), so the rasterizer can be reused for another draw.
+    pub fn clear(&mut self) {
+        self.stats = DrawStats::default();
+        self.triangles.clear();
+        self.varyings.clear();
+        self.bins.iter_mut().for_each(Vec::clear);
+    }
+
+    // the (inclusive) range of pixels a tile covers
+    fn tile_bounds(&self, tile: usize) -> (UVec2, UVec2) {
+        let tilemin = UVec2::new((tile % self.tiles_x * TILE_SIZE) as u32, (tile / self.tiles_x * TILE_SIZE) as u32);
+        let tilemax = (tilemin + UVec2::splat(TILE_SIZE as u32 - 1))
+            .min(UVec2::new(self.width as u32 - 1, self.height as u32 - 1));
+        (tilemin, tilemax)
+    }
+
+    // rasterizes a tile's triangles into a copy of its piece of the framebuffer
+    fn render_tile<T, S>(&self, tile: usize, framebuffer: &Framebuffer<T>, shader: &S) -> RenderedTile<T>
+    where
+        T: ColorSpace + Copy + Debug,
+        S: Shader<T, Varying = V> {
+
+        let (tilemin, tilemax) = self.tile_bounds(tile);
+        let mut tile_framebuffer = framebuffer.region(tilemin, tilemax);
+        let tile_width = tile_framebuffer.width;
+
+        for &idx in &self.bins[tile] {
+            let triangle = &self.triangles[idx as usize];
+            let varying = &self.varyings[triangle.varying];
+            let (bboxmin, bboxmax) = (triangle.bboxmin.max(tilemin), triangle.bboxmax.min(tilemax));
+
+            rasterize(&self.state, &triangle.screen_coords, &triangle.bary_coords, bboxmin, bboxmax, |p_x, p_y, p_z, bc_clip| {
+                let local_idx = (p_x - tilemin.x as usize) + (p_y - tilemin.y as usize)*tile_width;
+                shade_fragment(shader, varying, &self.state, bc_clip, p_z, &mut tile_framebuffer, local_idx);
+            });
+        }
+
+        RenderedTile { tile, framebuffer: tile_framebuffer }
+    }
+}
@@ -4,19 +4,23 @@ use glam::*;
 ///
 /// 1. model_view: Shifts the "camera" and translates a point's coordinate to its world position.
 ///
-/// 2. projection: Performs perspective deformation.
+/// 2. projection: Performs perspective deformation, taking view space into clip space.
 ///
-/// 3. viewport: Maps local coordinates into pixel coordinates.
+/// 3. viewport: Maps normalized device coordinates into pixel coordinates.
+///
+/// The homogeneous divide between clip space and NDC isn't part of any matrix;
+/// the pipeline does it (see `screen_transform`).
 #[derive(Clone, Copy)]
 pub struct Transform {
-    pub viewport: Affine3A,
-    pub projection: Affine3A,
-    pub model_view: Affine3A,
+    pub viewport: Mat4,
+    pub projection: Mat4,
+    pub model_view: Mat4,
 }
 
 impl Transform {
     /// Obtain the entire transform.
-    pub fn get_whole_transform(&self) -> Affine3A {
+    /// As it contains the projection, points must go through `project_point3` to apply the homogeneous divide.
+    pub fn get_whole_transform(&self) -> Mat4 {
         self.viewport * self.projection * self.model_view
     }
 
@@ -24,60 +28,102 @@ impl Transform {
         self.viewport.transform_point3(point)
     }
 
+    /// Transforms a clip space point into screen space (homogeneous divide + viewport).
+    pub fn screen_transform(&self, point: Vec4) -> Vec3 {
+        self.viewport_transform(point.xyz() / point.w)
+    }
+
+    pub fn clip_transform(&self, point: Vec3) -> Vec4 {
+        self.projection * self.model_view * point.extend(1.0)
+    }
+
     pub fn ndc_transform(&self, point: Vec3) -> Vec3 {
         (self.projection * self.model_view)
-            .transform_point3(point)
+            .project_point3(point)
     }
 
-    pub fn ndc_inv_tr_transform(&self, point: Vec3) -> Vec3 { // typically for normals
-        Mat4::from(self.projection * self.model_view)
+    pub fn view_transform(&self, point: Vec3) -> Vec3 {
+        self.model_view.transform_point3(point)
+    }
+
+    pub fn view_dir_transform(&self, dir: Vec3) -> Vec3 { // ie ignores translation
+        self.model_view.transform_vector3(dir)
+    }
+
+    pub fn view_normal_transform(&self, normal: Vec3) -> Vec3 { // typically for normals
+        Mat3::from_mat4(self.model_view)
             .inverse()
             .transpose()
-            .transform_point3(point)
+            * normal
+    }
+}
+
+/// The projection used to take view space into clip space.
+/// Angles are in degrees, like the `opengl` crate's camera.
+#[derive(Clone, Copy, Debug)]
+pub enum Projection {
+    Perspective { fov_y: f32, near: f32, far: f32 },
+    Orthographic { left: f32, right: f32, bottom: f32, top: f32, near: f32, far: f32 },
+}
+
+impl Default for Projection {
+    // same as the opengl crate
+    fn default() -> Self {
+        Projection::Perspective { fov_y: 45.0, near: 0.1, far: 100.0 }
+    }
+}
+
+impl Projection {
+    /// Obtain the projection matrix, using OpenGL's clip space conventions (ie z in [-w, w]).
+    pub fn matrix(&self, aspect_ratio: f32) -> Mat4 {
+        match *self {
+            Projection::Perspective { fov_y, near, far } =>
+                Mat4::perspective_rh_gl(fov_y.to_radians(), aspect_ratio, near, far),
+            Projection::Orthographic { left, right, bottom, top, near, far } =>
+                Mat4::orthographic_rh_gl(left, right, bottom, top, near, far)
+        }
     }
 }
 
 // initialize a transform.
 // the "camera" is positioned at `eye` and points to `centre`, vertically aligned to `up`, a normal vector.
 // the final image's pixels is constrained to the bounds of `height` and `width`
-pub fn initialize_transform(height: usize, width: usize, eye: Vec3, centre: Vec3, up: Vec3) -> Transform {
+pub fn initialize_transform(height: usize, width: usize, eye: Vec3, centre: Vec3, up: Vec3, projection: Projection) -> Transform {
     let model_view = lookat(eye, centre, up);
-    let projection = Affine3A::IDENTITY;
+    let projection = projection.matrix(width as f32 / height as f32);
     let viewport = viewport(width/8, height/8, width*3/4, height*3/4);
 
     Transform { model_view, projection, viewport }
 }
 
-// Return matrix for transforming [0, 1] coordinates into screen cube coordinates
-fn viewport(x: usize, y: usize, w: usize, h: usize) -> Affine3A {
-    let mut m = Affine3A::IDENTITY;
+/// Return matrix for transforming [-1, 1] coordinates into screen cube coordinates, ie onto the `w` x `h` pixels from (`x`, `y`).
+/// NOTE: z is flipped, since NDC has the near plane at -1 but our zbuffers treat greater values as closer
+pub fn viewport(x: usize, y: usize, w: usize, h: usize) -> Mat4 {
+    let mut m = Mat4::IDENTITY;
     let depth = 255.0; // idk the guy said so
 
-    m.translation[0] = x as f32 + w as f32/2.0;
-    m.translation[1] = y as f32 + h as f32/2.0;
-    m.translation[2] = depth / 2.0;
+    m.w_axis[0] = x as f32 + w as f32/2.0;
+    m.w_axis[1] = y as f32 + h as f32/2.0;
+    m.w_axis[2] = depth / 2.0;
 
     m.x_axis[0] = w as f32 / 2.0;
     m.y_axis[1] = h as f32 / 2.0;
-    m.z_axis[2] = depth / 2.0;
+    m.z_axis[2] = -depth / 2.0;
 
     m
 }
 
 // Calculate matrix to "move" camera
-fn lookat(eye: Vec3, centre: Vec3, up: Vec3) -> Affine3A {
-    let eye = Vec3::new(-eye.x, -eye.y, eye.z); // WHAT THE FUCK WHY??
+// NOTE: this is the same as `Mat4::look_at_rh`, so that we match the opengl crate
+fn lookat(eye: Vec3, centre: Vec3, up: Vec3) -> Mat4 {
     let z = (eye - centre).normalize();
-    let x = up.cross(z.clone()).normalize();
-    let y = z.cross(x.clone()).normalize();
-    let mut model_view = Affine3A::IDENTITY;
-
-    for i in 0..3 {
-        model_view.x_axis[i] = x[i];
-        model_view.y_axis[i] = y[i];
-        model_view.z_axis[i] = z[i];
-        model_view.translation[i] = -centre[i];
-    };
-
-    model_view
-}
\ No newline at end of file
+    let x = up.cross(z).normalize();
+    let y = z.cross(x);
+
+    Mat4::from_cols(
+        Vec4::new(x.x, y.x, z.x, 0.0),
+        Vec4::new(x.y, y.y, z.y, 0.0),
+        Vec4::new(x.z, y.z, z.z, 0.0),
+        Vec4::new(-x.dot(eye), -y.dot(eye), -z.dot(eye), 1.0),
+    )
+}